@@ -0,0 +1,217 @@
+//! Users, their roles, and write-protected ranges, read from a local config file.
+//!
+//! Each line of the file that isn't blank or a `#` comment is one of:
+//!
+//! - `user <name> <password> read-only|read-write`
+//! - `protect <range> <name>...`: only the named users may change cells in the range
+//!
+//! Once users are configured, a connection must send `login <name> <password>`
+//! before anything else. Passwords are stored as written, so keep the file
+//! readable only by whoever runs the server.
+
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+
+use crate::command::{CellRange, CellRef};
+
+/// Reply to anything a connection sends before logging in
+pub const NOT_LOGGED_IN: &str = "Not logged in";
+/// Reply to a `login` with an unknown user or the wrong password
+pub const BAD_LOGIN: &str = "Invalid user name or password";
+/// End of the reply refusing a read-only user any change
+const READ_ONLY: &str = "may not change the sheet";
+/// Part of the reply refusing a change to a protected range
+const WRITE_PROTECTED: &str = "is write-protected";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Role {
+    ReadOnly,
+    ReadWrite,
+}
+
+#[derive(Debug)]
+struct User {
+    password: String,
+    role: Role,
+}
+
+/// A range only some users may change
+#[derive(Debug)]
+struct Protection {
+    range: CellRange,
+    writers: Vec<String>,
+}
+
+#[derive(Debug, Default)]
+pub struct AccessControl {
+    users: HashMap<String, User>,
+    protections: Vec<Protection>,
+}
+
+/// Whether an error refuses a logged-in user something their role or a protected range
+/// doesn't allow
+pub fn is_refusal(error: &str) -> bool {
+    error.ends_with(READ_ONLY) || error.contains(WRITE_PROTECTED)
+}
+
+impl AccessControl {
+    /// Reads the users and protected ranges from a config file
+    pub fn load(path: &Path) -> Result<Self, String> {
+        let contents = fs::read_to_string(path)
+            .map_err(|e| format!("Could not read {}: {}", path.display(), e))?;
+        contents.parse()
+    }
+
+    /// Checks a user's password
+    pub fn login(&self, name: &str, password: &str) -> Result<(), String> {
+        match self.users.get(name) {
+            Some(user) if user.password == password => Ok(()),
+            _ => Err(BAD_LOGIN.to_string()),
+        }
+    }
+
+    /// Checks that `user` (`None` before logging in) may read the sheet
+    pub fn check_read(&self, user: Option<&str>) -> Result<(), String> {
+        self.role(user).map(drop)
+    }
+
+    /// Checks that `user` may change the sheet at all
+    pub fn check_write(&self, user: Option<&str>) -> Result<(), String> {
+        match self.role(user)? {
+            Role::ReadWrite => Ok(()),
+            Role::ReadOnly => Err(format!("{} {}", user.unwrap_or(""), READ_ONLY)),
+        }
+    }
+
+    /// Checks that `user` may change a cell
+    pub fn check_cell(&self, user: Option<&str>, cell: &CellRef) -> Result<(), String> {
+        self.check_write(user)?;
+        match self.blocking(user, |range| range.contains(cell)) {
+            Some(range) => Err(format!("{} {} by {}", cell.key(), WRITE_PROTECTED, range)),
+            None => Ok(()),
+        }
+    }
+
+    /// Checks that `user` may change every cell in a range
+    pub fn check_range(&self, user: Option<&str>, range: &CellRange) -> Result<(), String> {
+        self.check_write(user)?;
+        match self.blocking(user, |protected| protected.overlaps(range)) {
+            Some(protected) => Err(format!("{} {} by {}", range, WRITE_PROTECTED, protected)),
+            None => Ok(()),
+        }
+    }
+
+    /// Checks that `user` may move every cell on a sheet (`None` for the default sheet)
+    pub fn check_sheet(&self, user: Option<&str>, sheet: Option<&str>) -> Result<(), String> {
+        self.check_write(user)?;
+        match self.blocking(user, |range| range.sheet.as_deref() == sheet) {
+            Some(range) => Err(format!("{} {}", range, WRITE_PROTECTED)),
+            None => Ok(()),
+        }
+    }
+
+    fn role(&self, user: Option<&str>) -> Result<Role, String> {
+        user.and_then(|name| self.users.get(name))
+            .map(|user| user.role)
+            .ok_or_else(|| NOT_LOGGED_IN.to_string())
+    }
+
+    /// The first protected range matching `affects` that `user` may not write to
+    fn blocking(
+        &self,
+        user: Option<&str>,
+        affects: impl Fn(&CellRange) -> bool,
+    ) -> Option<&CellRange> {
+        self.protections
+            .iter()
+            .find(|p| affects(&p.range) && !user.is_some_and(|u| p.writers.iter().any(|w| w == u)))
+            .map(|p| &p.range)
+    }
+}
+
+impl std::str::FromStr for AccessControl {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut access = AccessControl::default();
+        for (number, line) in s.lines().enumerate() {
+            let error = || format!("Error in users file on line {}: {}", number + 1, line);
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let parts: Vec<&str> = line.split_whitespace().collect();
+            match parts.as_slice() {
+                ["user", name, password, role] => {
+                    let role = match *role {
+                        "read-only" => Role::ReadOnly,
+                        "read-write" => Role::ReadWrite,
+                        _ => return Err(error()),
+                    };
+                    let user = User {
+                        password: password.to_string(),
+                        role,
+                    };
+                    if access.users.insert(name.to_string(), user).is_some() {
+                        return Err(error());
+                    }
+                }
+                ["protect", range, writers @ ..] => access.protections.push(Protection {
+                    range: range.parse().map_err(|_| error())?,
+                    writers: writers.iter().map(|w| w.to_string()).collect(),
+                }),
+                _ => return Err(error()),
+            }
+        }
+        Ok(access)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CONFIG: &str = "
+        # Everyone can read; only alice may touch the totals
+        user alice pa55 read-write
+        user bob hunter2 read-write
+        user carol letmein read-only
+        protect Sales!A10_D10 alice
+    ";
+
+    #[test]
+    fn test_roles_and_protected_ranges() {
+        let access: AccessControl = CONFIG.parse().unwrap();
+        let cell = |s: &str| s.parse::<CellRef>().unwrap();
+
+        assert!(access.login("alice", "pa55").is_ok());
+        assert_eq!(access.login("alice", "nope"), Err(BAD_LOGIN.to_string()));
+        assert_eq!(access.check_read(None), Err(NOT_LOGGED_IN.to_string()));
+        assert!(access.check_read(Some("carol")).is_ok());
+        assert!(access
+            .check_cell(Some("carol"), &cell("A1"))
+            .is_err_and(|e| is_refusal(&e)));
+
+        assert!(access.check_cell(Some("bob"), &cell("Sales!A9")).is_ok());
+        assert!(access
+            .check_cell(Some("bob"), &cell("Sales!B10"))
+            .is_err_and(|e| is_refusal(&e)));
+        assert!(access.check_cell(Some("alice"), &cell("Sales!B10")).is_ok());
+        assert!(access
+            .check_range(Some("bob"), &"Sales!C1_C20".parse().unwrap())
+            .is_err());
+        assert!(access.check_sheet(Some("bob"), Some("Sales")).is_err());
+        assert!(access.check_sheet(Some("bob"), None).is_ok());
+    }
+
+    #[test]
+    fn test_rejects_malformed_lines() {
+        assert!("user alice pa55 admin".parse::<AccessControl>().is_err());
+        assert!("user alice pa55".parse::<AccessControl>().is_err());
+        assert!("protect A1_ alice".parse::<AccessControl>().is_err());
+        assert!("user a x read-only\nuser a y read-write"
+            .parse::<AccessControl>()
+            .is_err());
+    }
+}
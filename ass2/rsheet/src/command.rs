@@ -0,0 +1,372 @@
+//! Commands the server understands: rsheet_lib's `get` and `set`, extended
+//! with sheet-qualified cells, plus everything built on top of them.
+
+use std::path::PathBuf;
+use std::str::FromStr;
+
+use rsheet_lib::command::CellIdentifier;
+
+use crate::display::Format;
+use crate::formula::Function;
+use crate::structure::StructuralEdit;
+use crate::{cell_id_to_string, cell_key, is_sheet_name, parse_cell_id, split_sheet};
+
+/// Largest range a single `get` may ask for
+pub const MAX_RANGE_CELLS: usize = 10_000;
+
+/// Largest range a single `format` may apply to, as each cell keeps its own format
+pub const MAX_FORMAT_CELLS: usize = 100_000;
+
+/// A cell on a particular sheet, written "Sales!B2" (or just "B2" on the default sheet).
+/// Cells are stored, scheduled and tracked by this rather than by their keys.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct CellRef {
+    /// The sheet the cell is on, `None` for the default sheet
+    pub sheet: Option<String>,
+    pub id: CellIdentifier,
+}
+
+impl CellRef {
+    /// The key the cell is written as, e.g. "A1" or "Sales!B2"
+    pub fn key(&self) -> String {
+        cell_key(self.sheet.as_deref(), &self.id)
+    }
+}
+
+impl std::fmt::Display for CellRef {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if let Some(sheet) = &self.sheet {
+            write!(f, "{}!", sheet)?;
+        }
+        write!(f, "{}", cell_id_to_string(&self.id))
+    }
+}
+
+impl FromStr for CellRef {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (sheet, cell) = split_sheet(s);
+        match (sheet, parse_cell_id(cell)) {
+            (Some(sheet), _) if !is_sheet_name(sheet) => {
+                Err(format!("Error parsing sheet name: {}", sheet))
+            }
+            (sheet, Some(id)) => Ok(CellRef {
+                sheet: sheet.map(str::to_string),
+                id,
+            }),
+            (_, None) => Err(format!("Error parsing cell position: {}", s)),
+        }
+    }
+}
+
+/// An inclusive rectangle of cells on one sheet, written "A1_C10" or "Sales!A1_C10"
+/// (or just "A1" for one cell)
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CellRange {
+    /// The sheet the range is on, `None` for the default sheet
+    pub sheet: Option<String>,
+    pub start: CellIdentifier,
+    pub end: CellIdentifier,
+}
+
+impl CellRange {
+    /// Whether `cell` lies within the range
+    pub fn contains(&self, cell: &CellRef) -> bool {
+        self.sheet == cell.sheet
+            && (self.start.col..=self.end.col).contains(&cell.id.col)
+            && (self.start.row..=self.end.row).contains(&cell.id.row)
+    }
+
+    /// How many cells the range covers
+    pub fn cell_count(&self) -> usize {
+        let cols = (self.end.col - self.start.col) as usize + 1;
+        let rows = (self.end.row - self.start.row) as usize + 1;
+        cols.saturating_mul(rows)
+    }
+
+    /// Whether the two ranges share any cell
+    pub fn overlaps(&self, other: &CellRange) -> bool {
+        self.sheet == other.sheet
+            && self.start.col <= other.end.col
+            && other.start.col <= self.end.col
+            && self.start.row <= other.end.row
+            && other.start.row <= self.end.row
+    }
+
+    /// The key of a cell on the range's sheet
+    pub fn key(&self, id: &CellIdentifier) -> String {
+        cell_key(self.sheet.as_deref(), id)
+    }
+
+    /// A cell on the range's sheet
+    pub fn cell(&self, id: CellIdentifier) -> CellRef {
+        CellRef {
+            sheet: self.sheet.clone(),
+            id,
+        }
+    }
+
+    /// Every cell in the range, row by row
+    pub fn cells(&self) -> impl Iterator<Item = CellRef> + '_ {
+        (self.start.row..=self.end.row).flat_map(move |row| {
+            (self.start.col..=self.end.col).map(move |col| self.cell(CellIdentifier { col, row }))
+        })
+    }
+}
+
+impl From<&CellRef> for CellRange {
+    fn from(cell: &CellRef) -> Self {
+        CellRange {
+            sheet: cell.sheet.clone(),
+            start: cell.id,
+            end: cell.id,
+        }
+    }
+}
+
+impl FromStr for CellRange {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (sheet, cells) = split_sheet(s);
+        let (first, second) = cells.split_once('_').unwrap_or((cells, cells));
+        let (Some(a), Some(b)) = (parse_cell_id(first), parse_cell_id(second)) else {
+            return Err(format!("Error parsing cell range: {}", s));
+        };
+        if sheet.is_some_and(|sheet| !is_sheet_name(sheet)) {
+            return Err(format!("Error parsing cell range: {}", s));
+        }
+        // Normalise so `start` is always the top-left corner
+        Ok(CellRange {
+            sheet: sheet.map(str::to_string),
+            start: CellIdentifier {
+                col: a.col.min(b.col),
+                row: a.row.min(b.row),
+            },
+            end: CellIdentifier {
+                col: a.col.max(b.col),
+                row: a.row.max(b.row),
+            },
+        })
+    }
+}
+
+impl std::fmt::Display for CellRange {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if let Some(sheet) = &self.sheet {
+            write!(f, "{}!", sheet)?;
+        }
+        if self.start == self.end {
+            write!(f, "{}", cell_id_to_string(&self.start))
+        } else {
+            write!(
+                f,
+                "{}_{}",
+                cell_id_to_string(&self.start),
+                cell_id_to_string(&self.end)
+            )
+        }
+    }
+}
+
+/// A single request read from a connection
+pub enum Request {
+    /// `get A1`: reply with the cell's value once it is up to date; `get A1 formatted`
+    /// also replies with how to show it (see `display`)
+    Get { cell: CellRef, formatted: bool },
+    /// `get A1_D20`: reply with every value in the range, once all are up to date, as one
+    /// JSON array of rows, followed by another of how to show them if `formatted`
+    GetRange { range: CellRange, formatted: bool },
+    /// `set A1 <expr>`: replace the cell's expression
+    Set { cell: CellRef, expr: String },
+    /// `subscribe A1_C10`: push every recalculated value within the range
+    Subscribe { range: CellRange },
+    /// `unsubscribe A1_C10`: stop pushing values for a previously subscribed range
+    Unsubscribe { range: CellRange },
+    /// `import <file.csv> A1`: load a CSV file as constants with its first field at `top_left`
+    Import { path: PathBuf, top_left: CellRef },
+    /// `export A1_C10 <file.csv>`: write the evaluated values in a range to a CSV file
+    Export { range: CellRange, path: PathBuf },
+    /// `copy A1 A2_A100`: copy a cell's expression into a range, shifting relative references
+    Copy { source: CellRef, targets: CellRange },
+    /// `insert_row 5`, `delete_col C` etc.: move cells to make room for, or close the gap
+    /// left by, a whole row or column
+    Restructure { edit: StructuralEdit },
+    /// `history A1`: list every expression the cell has had, oldest first
+    History { cell: CellRef },
+    /// `undo` / `undo global`: revert this connection's (or anyone's) latest edit
+    Undo { global: bool },
+    /// `redo` / `redo global`: reapply this connection's (or anyone's) latest undone edit
+    Redo { global: bool },
+    /// `format A1_A10 currency:2 red<0`: set how clients should show every cell in the
+    /// range, replacing any earlier format
+    Format { range: CellRange, format: Format },
+    /// `define tax(x) = x * 0.1`: add a function formulas can call, replacing any
+    /// earlier definition of the same name
+    Define { function: Function },
+    /// `login <name> <password>`: act as a user from the server's users file
+    Login { user: String, password: String },
+    /// `stats`: report what the evaluators are doing, one counter per line
+    Stats,
+    /// `deps A1`: list the cells A1 reads, with their current values
+    Deps { cell: CellRef },
+    /// `dependents A1`: list the cells that read A1, with their current values
+    Dependents { cell: CellRef },
+    /// `explain A1`: show how the cell's value was computed, as a tree of its variables
+    /// and the cells they read (see `explain`)
+    Explain { cell: CellRef },
+    /// `begin`: hold this connection's `set`s back until `commit`
+    Begin,
+    /// `commit`: apply the held `set`s all at once
+    Commit,
+    /// `rollback`: discard the held `set`s
+    Rollback,
+    /// `replicate`: send the sheet as commands, then every logged change as it happens,
+    /// to a follower server (see `replicate`)
+    Replicate,
+}
+
+impl FromStr for Request {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = s.split_whitespace();
+        let request = match parts.next() {
+            Some("subscribe") => Request::Subscribe {
+                range: parse_only_range(&mut parts, s)?,
+            },
+            Some("unsubscribe") => Request::Unsubscribe {
+                range: parse_only_range(&mut parts, s)?,
+            },
+            Some(command @ ("history" | "deps" | "dependents" | "explain")) => {
+                let cell = match (parts.next(), parts.next()) {
+                    (Some(cell), None) => cell.parse()?,
+                    _ => return Err(format!("Error parsing request: {}", s)),
+                };
+                match command {
+                    "history" => Request::History { cell },
+                    "deps" => Request::Deps { cell },
+                    "dependents" => Request::Dependents { cell },
+                    _ => Request::Explain { cell },
+                }
+            }
+            Some("copy") => match (parts.next(), parts.next(), parts.next()) {
+                (Some(source), Some(targets), None) => Request::Copy {
+                    source: source.parse()?,
+                    targets: targets.parse()?,
+                },
+                _ => return Err(format!("Error parsing request: {}", s)),
+            },
+            Some("insert_row" | "delete_row" | "insert_col" | "delete_col") => {
+                Request::Restructure { edit: s.parse()? }
+            }
+            Some(command @ ("stats" | "begin" | "commit" | "rollback" | "replicate")) => {
+                if parts.next().is_some() {
+                    return Err(format!("Error parsing request: {}", s));
+                }
+                match command {
+                    "stats" => Request::Stats,
+                    "begin" => Request::Begin,
+                    "commit" => Request::Commit,
+                    "rollback" => Request::Rollback,
+                    _ => Request::Replicate,
+                }
+            }
+            Some("login") => match (parts.next(), parts.next(), parts.next()) {
+                (Some(user), Some(password), None) => Request::Login {
+                    user: user.to_string(),
+                    password: password.to_string(),
+                },
+                _ => return Err(format!("Error parsing request: {}", s)),
+            },
+            Some("format") => {
+                let rest = s.trim_start()["format".len()..].trim();
+                let (range, format) = rest.split_once(char::is_whitespace).unwrap_or((rest, ""));
+                Request::Format {
+                    range: range.parse()?,
+                    format: format.parse()?,
+                }
+            }
+            Some("define") => Request::Define {
+                function: s.trim_start()["define".len()..].parse()?,
+            },
+            Some("undo") => Request::Undo {
+                global: parse_scope(&mut parts, s)?,
+            },
+            Some("redo") => Request::Redo {
+                global: parse_scope(&mut parts, s)?,
+            },
+            // File names may contain spaces, so they take whatever the cell argument doesn't
+            Some("import") => {
+                let rest = s.trim_start()["import".len()..].trim();
+                let (path, cell) = rest
+                    .rsplit_once(char::is_whitespace)
+                    .ok_or_else(|| format!("Error parsing request: {}", s))?;
+                Request::Import {
+                    path: PathBuf::from(path.trim()),
+                    top_left: cell.parse()?,
+                }
+            }
+            Some("export") => {
+                let rest = s.trim_start()["export".len()..].trim();
+                let (range, path) = rest
+                    .split_once(char::is_whitespace)
+                    .ok_or_else(|| format!("Error parsing request: {}", s))?;
+                Request::Export {
+                    range: range.parse()?,
+                    path: PathBuf::from(path.trim()),
+                }
+            }
+            // Matches rsheet_lib's own `get`/`set` parsing, plus an optional sheet prefix
+            Some("get" | "set") => {
+                let mut parts = s.splitn(3, |c: char| c.is_ascii_whitespace());
+                let error = || format!("Error parsing request: {}", s);
+                let command = parts.next().unwrap_or_default();
+                let target = parts.next().ok_or_else(error)?;
+                match (command, parts.next()) {
+                    ("get", rest @ (None | Some("formatted"))) if target.contains('_') => {
+                        Request::GetRange {
+                            range: target.parse().map_err(|_| error())?,
+                            formatted: rest.is_some(),
+                        }
+                    }
+                    ("get", rest @ (None | Some("formatted"))) => Request::Get {
+                        cell: target.parse().map_err(|_| error())?,
+                        formatted: rest.is_some(),
+                    },
+                    ("set", Some(expr)) => Request::Set {
+                        cell: target.parse().map_err(|_| error())?,
+                        expr: expr.to_string(),
+                    },
+                    _ => return Err(error()),
+                }
+            }
+            _ => return Err(format!("Error parsing request: {}", s)),
+        };
+        Ok(request)
+    }
+}
+
+/// Parses the one remaining argument of a command as a range
+fn parse_only_range<'a>(
+    parts: &mut impl Iterator<Item = &'a str>,
+    request: &str,
+) -> Result<CellRange, String> {
+    match (parts.next(), parts.next()) {
+        (Some(range), None) => range.parse(),
+        _ => Err(format!("Error parsing request: {}", request)),
+    }
+}
+
+/// Parses the optional `global` argument of `undo` and `redo`
+fn parse_scope<'a>(
+    parts: &mut impl Iterator<Item = &'a str>,
+    request: &str,
+) -> Result<bool, String> {
+    match (parts.next(), parts.next()) {
+        (None, _) => Ok(false),
+        (Some("global"), None) => Ok(true),
+        _ => Err(format!("Error parsing request: {}", request)),
+    }
+}
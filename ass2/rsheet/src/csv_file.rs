@@ -0,0 +1,216 @@
+//! Reading and writing blocks of cells as CSV files.
+//!
+//! Clients name files relative to the one directory the server was started with
+//! (`--files-dir`); without one, `import` and `export` are turned off.
+
+use std::fs;
+use std::path::{Component, Path, PathBuf};
+
+use rsheet_lib::cell_value::CellValue;
+use rsheet_lib::command::CellIdentifier;
+
+/// Finds the file a client named `path` within `dir`, refusing any path that could
+/// lead outside it, whether written as one or through a symbolic link. The file is
+/// returned with every link already followed, and a link to a file that doesn't exist
+/// is refused, as creating the file would follow it wherever it points.
+pub fn resolve(dir: Option<&Path>, path: &Path) -> Result<PathBuf, String> {
+    let Some(dir) = dir else {
+        return Err("Import and export are disabled on this server".to_string());
+    };
+    let outside = || format!("{} is outside the files directory", path.display());
+    if path.as_os_str().is_empty()
+        || !path
+            .components()
+            .all(|c| matches!(c, Component::Normal(_) | Component::CurDir))
+    {
+        return Err(outside());
+    }
+
+    let canonical = |path: &Path| {
+        fs::canonicalize(path).map_err(|e| format!("Could not open {}: {}", path.display(), e))
+    };
+    let root = canonical(dir)?;
+    let resolved = dir.join(path);
+    let (Some(parent), Some(name)) = (resolved.parent(), resolved.file_name()) else {
+        return Err(outside());
+    };
+    let parent = canonical(parent)?;
+    if !parent.starts_with(&root) {
+        return Err(outside());
+    }
+    let file = parent.join(name);
+    match fs::symlink_metadata(&file) {
+        Ok(metadata) if metadata.file_type().is_symlink() => {
+            let target = fs::canonicalize(&file)
+                .map_err(|_| format!("{} links to a file that doesn't exist", path.display()))?;
+            if !target.starts_with(&root) {
+                return Err(outside());
+            }
+            Ok(target)
+        }
+        _ => Ok(file),
+    }
+}
+
+/// Reads a CSV file into constant cell expressions, placing its first field at
+/// `top_left`. Empty fields are skipped so they leave existing cells alone.
+pub fn read_cells(
+    path: &Path,
+    top_left: CellIdentifier,
+) -> Result<Vec<(CellIdentifier, String)>, String> {
+    let mut reader = csv::ReaderBuilder::new()
+        .has_headers(false)
+        .flexible(true)
+        .from_path(path)
+        .map_err(|e| format!("Could not open {}: {}", path.display(), e))?;
+
+    let mut cells = Vec::new();
+    for (row, record) in reader.records().enumerate() {
+        let record = record.map_err(|e| format!("Could not read {}: {}", path.display(), e))?;
+        for (col, field) in record.iter().enumerate() {
+            if field.is_empty() {
+                continue;
+            }
+            let id = CellIdentifier {
+                col: top_left.col + col as u32,
+                row: top_left.row + row as u32,
+            };
+            cells.push((id, constant_expr(field)));
+        }
+    }
+    Ok(cells)
+}
+
+/// Writes rows of evaluated cells to a CSV file, rendering errors as text
+pub fn write_cells(path: &Path, rows: &[Vec<CellValue>]) -> Result<(), String> {
+    let fail = |e: csv::Error| format!("Could not write {}: {}", path.display(), e);
+    let mut writer = csv::WriterBuilder::new()
+        .flexible(true)
+        .from_path(path)
+        .map_err(fail)?;
+    for row in rows {
+        let fields = row.iter().map(|value| match value {
+            CellValue::Int(i) => i.to_string(),
+            CellValue::String(s) => s.clone(),
+            CellValue::Error(e) => format!("Error: {}", e),
+            CellValue::None => String::new(),
+        });
+        writer.write_record(fields).map_err(fail)?;
+    }
+    writer
+        .flush()
+        .map_err(|e| format!("Could not write {}: {}", path.display(), e))
+}
+
+/// Turns a CSV field into an expression evaluating to the same number or string
+fn constant_expr(field: &str) -> String {
+    if let Ok(number) = field.trim().parse::<i64>() {
+        return number.to_string();
+    }
+    let escaped = field
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\r', "\\r")
+        .replace('\n', "\\n");
+    format!("\"{}\"", escaped)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("rsheet-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn test_constants_evaluate_to_the_field() {
+        assert_eq!(constant_expr(" 42 "), "42");
+        assert_eq!(constant_expr("-7"), "-7");
+        assert_eq!(constant_expr("4.5"), "\"4.5\"");
+        assert_eq!(constant_expr("say \"hi\"\\\n"), "\"say \\\"hi\\\"\\\\\\n\"");
+    }
+
+    #[test]
+    fn test_writes_then_reads_cells() {
+        let dir = temp_dir("csv");
+        let path = dir.join("cells.csv");
+        let rows = vec![
+            vec![
+                CellValue::Int(1),
+                CellValue::None,
+                CellValue::String("a,b".to_string()),
+            ],
+            vec![CellValue::Error("oops".to_string())],
+        ];
+        write_cells(&path, &rows).unwrap();
+        assert_eq!(
+            fs::read_to_string(&path).unwrap(),
+            "1,,\"a,b\"\nError: oops\n"
+        );
+
+        let id = |col, row| CellIdentifier { col, row };
+        assert_eq!(
+            read_cells(&path, id(1, 2)).unwrap(),
+            vec![
+                (id(1, 2), "1".to_string()),
+                (id(3, 2), "\"a,b\"".to_string()),
+                (id(1, 3), "\"Error: oops\"".to_string()),
+            ]
+        );
+        assert!(read_cells(&dir.join("missing.csv"), id(0, 0)).is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_only_resolves_paths_within_the_files_directory() {
+        let dir = temp_dir("resolve");
+        let root = fs::canonicalize(&dir).unwrap();
+        fs::create_dir_all(dir.join("sub")).unwrap();
+        assert_eq!(
+            resolve(Some(&dir), Path::new("sub/new.csv")),
+            Ok(root.join("sub/new.csv"))
+        );
+        for rejected in [
+            "/etc/passwd",
+            "../x.csv",
+            "sub/../../x.csv",
+            "",
+            "missing/x.csv",
+        ] {
+            assert!(
+                resolve(Some(&dir), Path::new(rejected)).is_err(),
+                "{}",
+                rejected
+            );
+        }
+        assert!(resolve(None, Path::new("x.csv")).is_err());
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::symlink;
+
+            symlink("/etc", dir.join("link")).unwrap();
+            assert!(resolve(Some(&dir), Path::new("link/passwd")).is_err());
+
+            // Exporting through a dangling link would create its target
+            let outside = temp_dir("resolve-outside").join("new.csv");
+            symlink(&outside, dir.join("dangling.csv")).unwrap();
+            assert!(resolve(Some(&dir), Path::new("dangling.csv")).is_err());
+            assert!(!outside.exists());
+            fs::remove_dir_all(outside.parent().unwrap()).unwrap();
+
+            // Links that stay within the directory are followed
+            fs::write(dir.join("sub/real.csv"), "1").unwrap();
+            symlink(dir.join("sub/real.csv"), dir.join("alias.csv")).unwrap();
+            assert_eq!(
+                resolve(Some(&dir), Path::new("alias.csv")),
+                Ok(root.join("sub/real.csv"))
+            );
+        }
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,314 @@
+//! How cells should be shown, set with `format` and stored alongside their values.
+//!
+//! A format is a number format followed by any number of conditional styles, e.g.
+//! `currency:2 red<0 bold>=1000`. The number format is one of `general` (the
+//! default), `number`, `currency` or `percent`, optionally with how many decimal
+//! places to show. Each style applies whenever the cell's value compares as given;
+//! the server only names the style, and clients decide what `red` looks like.
+//! Fractional results, which formulas give as strings such as `3.5`, are formatted
+//! and compared as numbers too.
+//!
+//! `get A1 formatted` follows the value with its display, as JSON:
+//!
+//! ```text
+//! A1 = -5
+//! A1.display = "{"styles":["red"],"text":"-$5.00"}"
+//! ```
+
+use std::fmt;
+use std::str::FromStr;
+
+use rsheet_lib::cell_value::CellValue;
+use serde_json::{json, Value};
+
+/// Most decimal places a number format may show
+const MAX_DECIMALS: usize = 10;
+
+/// How a cell's value is shown
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Format {
+    number: NumberFormat,
+    rules: Vec<Rule>,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+enum NumberFormat {
+    /// Values exactly as `get` replies with them
+    #[default]
+    General,
+    Number(usize),
+    Currency(usize),
+    Percent(usize),
+}
+
+/// A style that applies while the value compares to `threshold` as `comparison` says
+#[derive(Debug, Clone, PartialEq)]
+struct Rule {
+    style: String,
+    comparison: Comparison,
+    threshold: f64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Comparison {
+    Less,
+    LessOrEqual,
+    Greater,
+    GreaterOrEqual,
+    Equal,
+    NotEqual,
+}
+
+/// Comparisons as written, longest first so `<=` isn't read as `<`
+const COMPARISONS: [(&str, Comparison); 6] = [
+    ("<=", Comparison::LessOrEqual),
+    (">=", Comparison::GreaterOrEqual),
+    ("!=", Comparison::NotEqual),
+    ("<", Comparison::Less),
+    (">", Comparison::Greater),
+    ("=", Comparison::Equal),
+];
+
+/// Name of the reply that follows a value with how to show it, e.g. `A1.display`
+pub fn display_name(name: &str) -> String {
+    format!("{}.display", name)
+}
+
+/// The number a value holds. Formulas give fractional results as strings such as
+/// "3.5", so those count as numbers too.
+fn number(value: &CellValue) -> Option<f64> {
+    match value {
+        CellValue::Int(n) => Some(*n as f64),
+        CellValue::String(s) => s.trim().parse().ok().filter(|n: &f64| n.is_finite()),
+        CellValue::Error(_) | CellValue::None => None,
+    }
+}
+
+/// `n` with a fixed number of decimal places, and a sign only if it doesn't round to zero
+fn fixed(n: f64, decimals: usize, prefix: &str) -> String {
+    let digits = format!("{:.*}", decimals, n.abs());
+    let sign = if n < 0.0 && digits.bytes().any(|b| matches!(b, b'1'..=b'9')) {
+        "-"
+    } else {
+        ""
+    };
+    format!("{}{}{}", sign, prefix, digits)
+}
+
+impl Format {
+    /// The text and styles to show `value` with, as a JSON object
+    pub fn display(&self, value: &CellValue) -> Value {
+        let styles: Vec<&str> = match number(value) {
+            Some(n) => self
+                .rules
+                .iter()
+                .filter(|rule| rule.applies(n))
+                .map(|rule| rule.style.as_str())
+                .collect(),
+            None => Vec::new(),
+        };
+        json!({ "text": self.text(value), "styles": styles })
+    }
+
+    fn text(&self, value: &CellValue) -> String {
+        match (self.number, number(value)) {
+            (NumberFormat::Number(decimals), Some(n)) => fixed(n, decimals, ""),
+            (NumberFormat::Currency(decimals), Some(n)) => fixed(n, decimals, "$"),
+            (NumberFormat::Percent(decimals), Some(n)) => {
+                format!("{}%", fixed(n * 100.0, decimals, ""))
+            }
+            _ => match value {
+                CellValue::Int(n) => n.to_string(),
+                CellValue::String(s) | CellValue::Error(s) => s.clone(),
+                CellValue::None => String::new(),
+            },
+        }
+    }
+}
+
+impl Rule {
+    fn applies(&self, n: f64) -> bool {
+        match self.comparison {
+            Comparison::Less => n < self.threshold,
+            Comparison::LessOrEqual => n <= self.threshold,
+            Comparison::Greater => n > self.threshold,
+            Comparison::GreaterOrEqual => n >= self.threshold,
+            Comparison::Equal => n == self.threshold,
+            Comparison::NotEqual => n != self.threshold,
+        }
+    }
+}
+
+impl FromStr for Format {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let error = || format!("Error parsing format: {}", s.trim());
+        let mut format = Format::default();
+        for (i, part) in s.split_whitespace().enumerate() {
+            let (name, decimals) = match part.split_once(':') {
+                Some((name, decimals)) => (name, Some(decimals)),
+                None => (part, None),
+            };
+            let decimals = || match decimals {
+                Some(decimals) => decimals
+                    .parse()
+                    .ok()
+                    .filter(|&n| n <= MAX_DECIMALS)
+                    .ok_or_else(error),
+                None => Ok(2),
+            };
+            // Only the first word may be a number format
+            format.number = match name {
+                "general" if i == 0 && part == name => NumberFormat::General,
+                "number" if i == 0 => NumberFormat::Number(decimals()?),
+                "currency" if i == 0 => NumberFormat::Currency(decimals()?),
+                "percent" if i == 0 => NumberFormat::Percent(decimals()?),
+                _ => {
+                    format.rules.push(part.parse().map_err(|_| error())?);
+                    continue;
+                }
+            };
+        }
+        Ok(format)
+    }
+}
+
+impl FromStr for Rule {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (at, written, comparison) = COMPARISONS
+            .iter()
+            .filter_map(|&(written, comparison)| Some((s.find(written)?, written, comparison)))
+            .min_by_key(|&(at, written, _)| (at, std::cmp::Reverse(written.len())))
+            .ok_or(())?;
+        let style = &s[..at];
+        if style.is_empty() || !style.chars().all(|c| c.is_ascii_alphabetic() || c == '_') {
+            return Err(());
+        }
+        Ok(Rule {
+            style: style.to_string(),
+            comparison,
+            threshold: s[at + written.len()..]
+                .parse()
+                .ok()
+                .filter(|n: &f64| n.is_finite())
+                .ok_or(())?,
+        })
+    }
+}
+
+impl fmt::Display for Format {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.number {
+            NumberFormat::General => write!(f, "general")?,
+            NumberFormat::Number(decimals) => write!(f, "number:{}", decimals)?,
+            NumberFormat::Currency(decimals) => write!(f, "currency:{}", decimals)?,
+            NumberFormat::Percent(decimals) => write!(f, "percent:{}", decimals)?,
+        }
+        for rule in &self.rules {
+            let (written, _) = COMPARISONS
+                .iter()
+                .find(|(_, comparison)| *comparison == rule.comparison)
+                .expect("every comparison can be written");
+            write!(f, " {}{}{}", rule.style, written, rule.threshold)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn shown(format: &str, value: CellValue) -> Value {
+        format.parse::<Format>().unwrap().display(&value)
+    }
+
+    #[test]
+    fn test_shows_numbers_with_their_format_and_styles() {
+        assert_eq!(
+            shown("currency red<0 bold>=1000", CellValue::Int(-5)),
+            json!({ "text": "-$5.00", "styles": ["red"] })
+        );
+        assert_eq!(
+            shown("percent:1", CellValue::Int(3)),
+            json!({ "text": "300.0%", "styles": [] })
+        );
+        assert_eq!(
+            shown("number:0 grey=0", CellValue::Int(0)),
+            json!({ "text": "0", "styles": ["grey"] })
+        );
+        assert_eq!(
+            shown("red<0", CellValue::String("n/a".to_string())),
+            json!({ "text": "n/a", "styles": [] })
+        );
+        assert_eq!(
+            shown("general", CellValue::None),
+            json!({ "text": "", "styles": [] })
+        );
+    }
+
+    #[test]
+    fn test_shows_fractional_results_as_numbers() {
+        let fraction = |s: &str| CellValue::String(s.to_string());
+        assert_eq!(
+            shown("currency:2 red<0", fraction("3.5")),
+            json!({ "text": "$3.50", "styles": [] })
+        );
+        assert_eq!(
+            shown("currency:2 red<0", fraction("-0.5")),
+            json!({ "text": "-$0.50", "styles": ["red"] })
+        );
+        assert_eq!(
+            shown("percent:0 bold>=0.25", fraction("0.25")),
+            json!({ "text": "25%", "styles": ["bold"] })
+        );
+        assert_eq!(
+            shown("number:1 grey<0.1", fraction("-0.04")),
+            json!({ "text": "0.0", "styles": ["grey"] })
+        );
+        assert_eq!(
+            shown("general red<1.5", fraction("1.25")),
+            json!({ "text": "1.25", "styles": ["red"] })
+        );
+        assert_eq!(
+            shown("number:0 red<0", fraction("inf")),
+            json!({ "text": "inf", "styles": [] })
+        );
+    }
+
+    #[test]
+    fn test_parses_what_it_writes() {
+        for written in [
+            "general",
+            "currency:2 red<0 green>=100",
+            "percent:0 blue!=1",
+            "number:3 red<-0.5 green>=2.25",
+        ] {
+            let format: Format = written.parse().unwrap();
+            assert_eq!(format.to_string(), written);
+        }
+        assert_eq!(
+            "currency".parse::<Format>().unwrap().to_string(),
+            "currency:2"
+        );
+        assert_eq!(
+            "red<=-1".parse::<Format>().unwrap().to_string(),
+            "general red<=-1"
+        );
+        for rejected in [
+            "currency:x",
+            "percent:11",
+            "red<",
+            "<0",
+            "red 0",
+            "red<0 currency",
+            "red<1.2.3",
+            "red<inf",
+        ] {
+            assert!(rejected.parse::<Format>().is_err(), "{}", rejected);
+        }
+    }
+}
@@ -0,0 +1,196 @@
+//! Explaining how a cell came to have its value, for `explain`.
+//!
+//! The explanation is a tree sent one reply per line, indented by its name: the
+//! cell and its value, then its expression, then each variable its formula was
+//! given with the value it was given. Cells those variables read are broken down
+//! the same way beneath them:
+//!
+//! ```text
+//! A1 = 12
+//!   expr = "sum(B1_B2) + C1"
+//!   B1_B2 = "[5,3]"
+//!     B1 = 5
+//!       expr = "C1 + 1"
+//!       C1 = 4
+//!         expr = "4"
+//!   C1 = 4
+//! ```
+
+use std::collections::HashSet;
+
+use rsheet_lib::cell_expr::CellArgument;
+use rsheet_lib::cell_value::CellValue;
+use rsheet_lib::replies::Reply;
+use serde_json::Value;
+
+use crate::command::{CellRange, CellRef};
+use crate::store::Store;
+use crate::{http, read_variables};
+
+/// How many levels below the cell being explained are broken down
+pub const MAX_EXPLAIN_DEPTH: usize = 5;
+
+/// Longest explanation sent for one cell, in lines
+pub const MAX_EXPLAIN_LINES: usize = 1_000;
+
+/// Explains the value of the cell at `at`, as it is now
+pub fn explain(store: &Store, at: &CellRef) -> Vec<Reply> {
+    let mut lines = Vec::new();
+    explain_cell(store, at, 0, &mut HashSet::new(), &mut lines);
+    if lines.len() > MAX_EXPLAIN_LINES {
+        lines.truncate(MAX_EXPLAIN_LINES);
+        lines.push(Reply::Error(format!(
+            "Explanation of {} cut short after {} lines",
+            at, MAX_EXPLAIN_LINES
+        )));
+    }
+    lines
+}
+
+fn explain_cell(
+    store: &Store,
+    at: &CellRef,
+    depth: usize,
+    explained: &mut HashSet<CellRef>,
+    lines: &mut Vec<Reply>,
+) {
+    let indent = "  ".repeat(depth);
+    lines.push(Reply::Value(format!("{}{}", indent, at), store.value(at)));
+    let Some(expr) = store.expr(at) else {
+        return;
+    };
+    // A cell already broken down elsewhere in the tree is only listed
+    if depth == MAX_EXPLAIN_DEPTH
+        || lines.len() > MAX_EXPLAIN_LINES
+        || !explained.insert(at.clone())
+    {
+        return;
+    }
+    lines.push(Reply::Value(
+        format!("{}  expr", indent),
+        CellValue::String(expr.to_string()),
+    ));
+
+    for (var, arg) in read_variables(store, at, expr) {
+        let Ok(range) = var.parse::<CellRange>() else {
+            continue;
+        };
+        if range.start == range.end {
+            explain_cell(store, &range.cell(range.start), depth + 1, explained, lines);
+            continue;
+        }
+        lines.push(Reply::Value(
+            format!("{}  {}", indent, var),
+            argument_value(arg),
+        ));
+        // Constants in a range are already shown by its value
+        let formulas: Vec<CellRef> = store
+            .within(range.sheet.as_deref(), range.start, range.end)
+            .map(|(&id, _)| range.cell(id))
+            .filter(|cell| store.deps.precedents(cell).next().is_some())
+            .collect();
+        for cell in formulas {
+            explain_cell(store, &cell, depth + 2, explained, lines);
+        }
+    }
+}
+
+/// A variable's value as a single value, with vectors and matrices as JSON arrays
+fn argument_value(arg: CellArgument) -> CellValue {
+    let array = |values: Vec<CellValue>| {
+        Value::Array(values.into_iter().map(http::value_to_json).collect())
+    };
+    match arg {
+        CellArgument::Value(value) => value,
+        CellArgument::Vector(values) => CellValue::String(array(values).to_string()),
+        CellArgument::Matrix(rows) => {
+            CellValue::String(Value::Array(rows.into_iter().map(array).collect()).to_string())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sheet(cells: &[(&str, &str, CellValue)]) -> Store {
+        let mut store = Store::default();
+        for (key, expr, value) in cells {
+            let at = at(key);
+            store.update(&at, |cell| {
+                cell.expr = Some(expr.to_string());
+                cell.value = value.clone();
+            });
+            let precedents = crate::expand_dependencies(&at, expr);
+            store.deps.set_precedents(&at, precedents);
+        }
+        store
+    }
+
+    fn at(key: &str) -> CellRef {
+        key.parse().unwrap()
+    }
+
+    fn names(replies: &[Reply]) -> Vec<&str> {
+        replies
+            .iter()
+            .map(|reply| match reply {
+                Reply::Value(name, _) => name.as_str(),
+                Reply::Error(e) => e.as_str(),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_breaks_down_variables_and_the_cells_they_read() {
+        let store = sheet(&[
+            ("A1", "sum(B1_B2) + C1 + C1", CellValue::Int(12)),
+            ("B1", "C1 + 1", CellValue::Int(5)),
+            ("B2", "3", CellValue::Int(3)),
+            ("C1", "4", CellValue::Int(4)),
+        ]);
+        let replies = explain(&store, &at("A1"));
+        assert_eq!(
+            names(&replies),
+            vec![
+                "A1",
+                "  expr",
+                "  B1_B2",
+                "    B1",
+                "      expr",
+                "      C1",
+                "        expr",
+                "  C1",
+            ]
+        );
+        assert_eq!(
+            replies[2],
+            Reply::Value(
+                "  B1_B2".to_string(),
+                CellValue::String("[5,3]".to_string())
+            )
+        );
+        assert_eq!(names(&explain(&store, &at("D1"))), vec!["D1"]);
+    }
+
+    #[test]
+    fn test_stops_at_the_depth_limit_and_on_cycles() {
+        let chain: Vec<(String, String)> = (1..=10)
+            .map(|row| (format!("A{}", row), format!("A{}", row + 1)))
+            .chain([("A11".to_string(), "A1".to_string())])
+            .collect();
+        let cells: Vec<_> = chain
+            .iter()
+            .map(|(key, expr)| (key.as_str(), expr.as_str(), CellValue::None))
+            .collect();
+        let store = sheet(&cells);
+        let replies = explain(&store, &at("A1"));
+        assert_eq!(replies.len(), 2 * MAX_EXPLAIN_DEPTH + 1);
+
+        let cycle = sheet(&[("A1", "B1", CellValue::None), ("B1", "A1", CellValue::None)]);
+        assert_eq!(
+            names(&explain(&cycle, &at("A1"))),
+            vec!["A1", "  expr", "  B1", "    expr", "    A1"]
+        );
+    }
+}
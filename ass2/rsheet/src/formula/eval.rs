@@ -0,0 +1,660 @@
+//! Evaluating parsed formulas against the values of the cells they read.
+//!
+//! Built-in functions: `IF(cond, then, [else])`, `SUM`, `AVERAGE`, `MIN`,
+//! `MAX`, `COUNT`, `VLOOKUP(value, table, column, [approximate])`,
+//! `SLEEP_THEN(millis, value)`, and the volatile `NOW()` (whole seconds since
+//! the Unix epoch) and `RAND()` (a fraction from 0 up to 1). Anything else is
+//! looked up among the functions defined with `define`.
+
+use std::borrow::Cow;
+use std::cell::Cell;
+use std::cmp::Ordering;
+use std::collections::hash_map::RandomState;
+use std::collections::HashMap;
+use std::hash::{BuildHasher, Hasher};
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use rsheet_lib::cell_expr::CellArgument;
+use rsheet_lib::cell_value::CellValue;
+use rsheet_lib::command::CellIdentifier;
+
+use super::parse::parse;
+use super::{BinaryOp, Expr, FormulaError, Function, Functions, UnaryOp};
+use crate::{cell_key, parse_cell_id, split_sheet};
+
+/// Whole numbers beyond this can't be stored exactly as an integer
+const MAX_EXACT_INT: f64 = 9_007_199_254_740_992.0;
+
+/// Functions every formula can call, which definitions may not replace
+pub(super) const BUILTINS: [&str; 10] = [
+    "IF",
+    "SUM",
+    "AVERAGE",
+    "MIN",
+    "MAX",
+    "COUNT",
+    "VLOOKUP",
+    "SLEEP_THEN",
+    "NOW",
+    "RAND",
+];
+
+/// Built-ins whose result changes without any of their arguments changing
+pub(super) const VOLATILE: [&str; 2] = ["NOW", "RAND"];
+
+/// Built-ins that hold up the evaluator calling them
+pub(super) const SLOW: [&str; 1] = ["SLEEP_THEN"];
+
+/// Deepest evaluation may recurse, counting every operator and call both in the formula
+/// and in the bodies of the defined functions it calls. The parser only bounds each
+/// expression on its own, so without this a recursive definition could overflow an
+/// evaluator's stack.
+const MAX_EVAL_DEPTH: usize = 320;
+
+/// An intermediate value while evaluating a formula
+#[derive(Debug, Clone, PartialEq)]
+enum Value {
+    Number(f64),
+    Text(String),
+    Empty,
+    /// The rows of a range; only functions accept these
+    Range(Vec<Vec<CellValue>>),
+}
+
+type Result<T> = std::result::Result<T, FormulaError>;
+
+/// Everything a formula can see while it is evaluated
+struct Env<'a> {
+    context: &'a HashMap<String, CellArgument>,
+    functions: &'a Functions,
+    /// Arguments bound to the parameters of the defined function being evaluated
+    locals: HashMap<&'a str, Value>,
+    /// How deeply evaluation has recursed, shared with the bodies of called functions
+    depth: &'a Cell<usize>,
+}
+
+/// Evaluates a formula written in a cell on `current_sheet`. `context` holds the value of
+/// every reference in the formula, keyed by its qualified name (see `Reference::qualified`).
+pub fn evaluate(
+    expr: &str,
+    current_sheet: Option<&str>,
+    context: &HashMap<String, CellArgument>,
+    functions: &Functions,
+) -> CellValue {
+    let env = Env {
+        context,
+        functions,
+        locals: HashMap::new(),
+        depth: &Cell::new(0),
+    };
+    let value = parse(expr, current_sheet).and_then(|parsed| eval(&parsed, &env));
+    match value {
+        Ok(Value::Number(n)) => number_to_cell(n),
+        Ok(Value::Text(s)) => CellValue::String(s),
+        Ok(Value::Empty) => CellValue::None,
+        Ok(Value::Range(_)) => CellValue::Error(
+            FormulaError::Value("a range can't be a cell's value".to_string()).to_string(),
+        ),
+        Err(e) => CellValue::Error(e.to_string()),
+    }
+}
+
+/// Stores whole numbers as integers and anything else as a decimal string
+fn number_to_cell(n: f64) -> CellValue {
+    if !n.is_finite() {
+        CellValue::Error(FormulaError::Num.to_string())
+    } else if n.fract() == 0.0 && n.abs() < MAX_EXACT_INT {
+        CellValue::Int(n as i64)
+    } else {
+        CellValue::String(format_number(n))
+    }
+}
+
+fn format_number(n: f64) -> String {
+    if n.fract() == 0.0 && n.abs() < MAX_EXACT_INT {
+        return (n as i64).to_string();
+    }
+    // Round away float noise such as 0.30000000000000004
+    let fixed = format!("{:.12}", n);
+    fixed
+        .trim_end_matches('0')
+        .trim_end_matches('.')
+        .to_string()
+}
+
+fn from_cell(value: &CellValue) -> Result<Value> {
+    Ok(match value {
+        CellValue::Int(i) => Value::Number(*i as f64),
+        CellValue::String(s) => Value::Text(s.clone()),
+        CellValue::None => Value::Empty,
+        CellValue::Error(e) => {
+            let Ok(error) = e.parse();
+            return Err(error);
+        }
+    })
+}
+
+/// Looks up a reference, laying vectors out in the direction the range runs. Errors in
+/// the cells it covers are tagged with the cell they came from.
+fn lookup(name: &str, context: &HashMap<String, CellArgument>) -> Result<Value> {
+    let Some(arg) = context.get(name) else {
+        return Ok(Value::Empty);
+    };
+    let (sheet, cells) = split_sheet(name);
+    let (start, end) = cells.split_once('_').unwrap_or((cells, cells));
+    let (Some(start), Some(end)) = (parse_cell_id(start), parse_cell_id(end)) else {
+        return Ok(Value::Empty);
+    };
+    // `row` and `col` are offsets from the top-left of the reference
+    let tag = |row: usize, col: usize, value: &CellValue| match value {
+        CellValue::Error(e) => {
+            let Ok(error) = e.parse::<FormulaError>();
+            let cell = CellIdentifier {
+                col: start.col + col as u32,
+                row: start.row + row as u32,
+            };
+            CellValue::Error(error.passed_on_from(&cell_key(sheet, &cell)).to_string())
+        }
+        value => value.clone(),
+    };
+    Ok(Value::Range(match arg {
+        CellArgument::Value(value) => return from_cell(&tag(0, 0, value)),
+        CellArgument::Vector(values) if start.col == end.col => values
+            .iter()
+            .enumerate()
+            .map(|(row, value)| vec![tag(row, 0, value)])
+            .collect(),
+        CellArgument::Vector(values) => vec![values
+            .iter()
+            .enumerate()
+            .map(|(col, value)| tag(0, col, value))
+            .collect()],
+        CellArgument::Matrix(rows) => rows
+            .iter()
+            .enumerate()
+            .map(|(row, values)| {
+                values
+                    .iter()
+                    .enumerate()
+                    .map(|(col, value)| tag(row, col, value))
+                    .collect()
+            })
+            .collect(),
+    }))
+}
+
+/// Reads text as a number if it is one, as decimal results are stored as text
+fn parse_number(s: &str) -> Option<f64> {
+    s.trim().parse().ok().filter(|n: &f64| n.is_finite())
+}
+
+fn to_number(value: &Value) -> Result<f64> {
+    match value {
+        Value::Number(n) => Ok(*n),
+        Value::Empty => Ok(0.0),
+        Value::Text(s) => {
+            parse_number(s).ok_or_else(|| FormulaError::Value(format!("\"{}\" is not a number", s)))
+        }
+        Value::Range(_) => Err(FormulaError::Value(
+            "a range can only be passed to a function".to_string(),
+        )),
+    }
+}
+
+fn to_text(value: &Value) -> Result<String> {
+    match value {
+        Value::Number(n) => Ok(format_number(*n)),
+        Value::Text(s) => Ok(s.clone()),
+        Value::Empty => Ok(String::new()),
+        Value::Range(_) => Err(FormulaError::Value(
+            "a range can only be passed to a function".to_string(),
+        )),
+    }
+}
+
+fn truthy(value: &Value) -> Result<bool> {
+    to_number(value).map(|n| n != 0.0)
+}
+
+fn boolean(b: bool) -> Value {
+    Value::Number(if b { 1.0 } else { 0.0 })
+}
+
+/// Reads text holding a number as that number, so decimal results compare as numbers
+fn numeric(value: &Value) -> Cow<'_, Value> {
+    match value {
+        Value::Text(s) => {
+            parse_number(s).map_or(Cow::Borrowed(value), |n| Cow::Owned(Value::Number(n)))
+        }
+        _ => Cow::Borrowed(value),
+    }
+}
+
+/// Orders two values: numbers numerically, text lexically, and numbers before text
+fn compare(a: &Value, b: &Value) -> Result<Ordering> {
+    let (a, b) = (&*numeric(a), &*numeric(b));
+    Ok(match (a, b) {
+        (Value::Text(a), Value::Text(b)) => a.cmp(b),
+        (Value::Text(_), Value::Empty) => to_text(a)?.cmp(&String::new()),
+        (Value::Empty, Value::Text(_)) => String::new().cmp(&to_text(b)?),
+        (Value::Text(_), _) => Ordering::Greater,
+        (_, Value::Text(_)) => Ordering::Less,
+        _ => to_number(a)?
+            .partial_cmp(&to_number(b)?)
+            .unwrap_or(Ordering::Equal),
+    })
+}
+
+/// Evaluates one level of an expression, giving up once evaluation is too deep
+fn eval(expr: &Expr, env: &Env) -> Result<Value> {
+    let depth = env.depth.get();
+    if depth >= MAX_EVAL_DEPTH {
+        return Err(FormulaError::Value(format!(
+            "formula recursed more than {} deep",
+            MAX_EVAL_DEPTH
+        )));
+    }
+    env.depth.set(depth + 1);
+    let value = eval_expr(expr, env);
+    env.depth.set(depth);
+    value
+}
+
+fn eval_expr(expr: &Expr, env: &Env) -> Result<Value> {
+    match expr {
+        Expr::Number(n) => Ok(Value::Number(*n)),
+        Expr::Text(s) => Ok(Value::Text(s.clone())),
+        Expr::Ref(name) => lookup(name, env.context),
+        Expr::Error(e) => Err(e.clone()),
+        Expr::Name(name) => env
+            .locals
+            .get(name.as_str())
+            .cloned()
+            .ok_or_else(|| FormulaError::Name(name.clone())),
+        Expr::Unary(op, operand) => unary(*op, &eval(operand, env)?),
+        Expr::Binary(op @ (BinaryOp::And | BinaryOp::Or), lhs, rhs) => logical(*op, lhs, rhs, env),
+        Expr::Binary(op, lhs, rhs) => binary(*op, &eval(lhs, env)?, &eval(rhs, env)?),
+        Expr::Call(name, args) => call(name, args, env),
+    }
+}
+
+fn unary(op: UnaryOp, value: &Value) -> Result<Value> {
+    match op {
+        UnaryOp::Neg => Ok(Value::Number(-to_number(value)?)),
+        UnaryOp::Plus => Ok(Value::Number(to_number(value)?)),
+        UnaryOp::Not => Ok(boolean(!truthy(value)?)),
+    }
+}
+
+/// `&&` and `||`, which only evaluate the right side when it decides the result
+fn logical(op: BinaryOp, lhs: &Expr, rhs: &Expr, env: &Env) -> Result<Value> {
+    let lhs = truthy(&eval(lhs, env)?)?;
+    Ok(boolean(match op {
+        BinaryOp::And => lhs && truthy(&eval(rhs, env)?)?,
+        _ => lhs || truthy(&eval(rhs, env)?)?,
+    }))
+}
+
+fn binary(op: BinaryOp, a: &Value, b: &Value) -> Result<Value> {
+    let number = |n: f64| {
+        if n.is_finite() {
+            Ok(Value::Number(n))
+        } else {
+            Err(FormulaError::Num)
+        }
+    };
+    let is_word = |v: &Value| matches!(v, Value::Text(s) if parse_number(s).is_none());
+
+    match op {
+        // `+` joins text, as it always has
+        BinaryOp::Add if is_word(a) || is_word(b) => {
+            Ok(Value::Text(format!("{}{}", to_text(a)?, to_text(b)?)))
+        }
+        BinaryOp::Add => number(to_number(a)? + to_number(b)?),
+        BinaryOp::Sub => number(to_number(a)? - to_number(b)?),
+        BinaryOp::Mul => number(to_number(a)? * to_number(b)?),
+        BinaryOp::Div | BinaryOp::Rem => {
+            let divisor = to_number(b)?;
+            if divisor == 0.0 {
+                return Err(FormulaError::DivZero);
+            }
+            let dividend = to_number(a)?;
+            number(if op == BinaryOp::Div {
+                dividend / divisor
+            } else {
+                dividend % divisor
+            })
+        }
+        BinaryOp::Pow => number(to_number(a)?.powf(to_number(b)?)),
+        BinaryOp::Concat => Ok(Value::Text(format!("{}{}", to_text(a)?, to_text(b)?))),
+        BinaryOp::Eq => Ok(boolean(compare(a, b)? == Ordering::Equal)),
+        BinaryOp::Ne => Ok(boolean(compare(a, b)? != Ordering::Equal)),
+        BinaryOp::Lt => Ok(boolean(compare(a, b)? == Ordering::Less)),
+        BinaryOp::Le => Ok(boolean(compare(a, b)? != Ordering::Greater)),
+        BinaryOp::Gt => Ok(boolean(compare(a, b)? == Ordering::Greater)),
+        BinaryOp::Ge => Ok(boolean(compare(a, b)? != Ordering::Less)),
+        BinaryOp::And => Ok(boolean(truthy(a)? && truthy(b)?)),
+        BinaryOp::Or => Ok(boolean(truthy(a)? || truthy(b)?)),
+    }
+}
+
+fn arity(name: &str, args: &[Expr], min: usize, max: usize) -> Result<()> {
+    if (min..=max).contains(&args.len()) {
+        Ok(())
+    } else {
+        Err(FormulaError::Value(format!(
+            "wrong number of arguments to {}",
+            name
+        )))
+    }
+}
+
+/// Calls a built-in or defined function. Each built-in is evaluated in a function of
+/// its own, which keeps this frame, and so every level of a deep formula, small.
+fn call(name: &str, args: &[Expr], env: &Env) -> Result<Value> {
+    match name {
+        "IF" => {
+            arity(name, args, 2, 3)?;
+            condition(args, env)
+        }
+        "SUM" | "AVERAGE" | "MIN" | "MAX" | "COUNT" => aggregate(name, numbers(args, env)?),
+        "VLOOKUP" => {
+            arity(name, args, 3, 4)?;
+            vlookup(args, env)
+        }
+        "SLEEP_THEN" => {
+            arity(name, args, 2, 2)?;
+            sleep_then(args, env)
+        }
+        "NOW" => {
+            arity(name, args, 0, 0)?;
+            Ok(Value::Number(now()))
+        }
+        "RAND" => {
+            arity(name, args, 0, 0)?;
+            Ok(Value::Number(random_fraction()))
+        }
+        _ => match env.functions.get(name) {
+            Some(function) => call_defined(function, args, env),
+            None => Err(FormulaError::Name(format!("{}()", name))),
+        },
+    }
+}
+
+fn condition(args: &[Expr], env: &Env) -> Result<Value> {
+    if truthy(&eval(&args[0], env)?)? {
+        eval(&args[1], env)
+    } else {
+        args.get(2)
+            .map_or(Ok(boolean(false)), |otherwise| eval(otherwise, env))
+    }
+}
+
+fn aggregate(name: &str, numbers: Vec<f64>) -> Result<Value> {
+    let count = numbers.len() as f64;
+    Ok(Value::Number(match name {
+        "SUM" => numbers.iter().sum(),
+        "AVERAGE" if numbers.is_empty() => return Err(FormulaError::DivZero),
+        "AVERAGE" => numbers.iter().sum::<f64>() / count,
+        "MIN" => numbers.into_iter().reduce(f64::min).unwrap_or(0.0),
+        "MAX" => numbers.into_iter().reduce(f64::max).unwrap_or(0.0),
+        _ => count,
+    }))
+}
+
+fn vlookup(args: &[Expr], env: &Env) -> Result<Value> {
+    let needle = eval(&args[0], env)?;
+    let Value::Range(table) = eval(&args[1], env)? else {
+        return Err(FormulaError::Value(
+            "VLOOKUP needs a range to search".to_string(),
+        ));
+    };
+    let column = to_number(&eval(&args[2], env)?)?;
+    let approximate = match args.get(3) {
+        Some(arg) => truthy(&eval(arg, env)?)?,
+        None => true,
+    };
+    lookup_row(&needle, &table, column, approximate)
+}
+
+fn sleep_then(args: &[Expr], env: &Env) -> Result<Value> {
+    let millis = to_number(&eval(&args[0], env)?)?;
+    thread::sleep(Duration::from_millis(millis.max(0.0) as u64));
+    eval(&args[1], env)
+}
+
+/// Whole seconds since the Unix epoch
+fn now() -> f64 {
+    let since_epoch = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    since_epoch.as_secs() as f64
+}
+
+/// A fraction from 0 up to (but excluding) 1. Each `RandomState` is freshly keyed, so
+/// hashing nothing with it gives unpredictable bits without a dependency on `rand`.
+fn random_fraction() -> f64 {
+    let bits = RandomState::new().build_hasher().finish();
+    // The top 53 bits fill an f64's mantissa exactly
+    (bits >> 11) as f64 / (1u64 << 53) as f64
+}
+
+/// Calls a function defined with `define`, binding its parameters to the evaluated arguments
+fn call_defined(function: &Function, args: &[Expr], env: &Env) -> Result<Value> {
+    let name = function.key();
+    arity(&name, args, function.params.len(), function.params.len())?;
+    let mut locals = HashMap::new();
+    for (param, arg) in function.params.iter().zip(args) {
+        locals.insert(param.as_str(), eval(arg, env)?);
+    }
+    let inner = Env {
+        context: env.context,
+        functions: env.functions,
+        locals,
+        depth: env.depth,
+    };
+    eval(&function.body, &inner)
+}
+
+/// Collects the numbers an aggregate function works over: scalar arguments must be
+/// numbers, while ranges contribute their numeric cells and skip everything else
+fn numbers(args: &[Expr], env: &Env) -> Result<Vec<f64>> {
+    let mut numbers = Vec::new();
+    for arg in args {
+        match eval(arg, env)? {
+            Value::Range(rows) => {
+                for cell in rows.iter().flatten() {
+                    match from_cell(cell)? {
+                        Value::Number(n) => numbers.push(n),
+                        Value::Text(s) => numbers.extend(parse_number(&s)),
+                        _ => {}
+                    }
+                }
+            }
+            value => numbers.push(to_number(&value)?),
+        }
+    }
+    Ok(numbers)
+}
+
+/// Finds the row whose first cell matches `needle` and returns its `column`th cell
+/// (1-indexed). An approximate lookup takes the last row not greater than `needle`,
+/// assuming the first column is sorted.
+fn lookup_row(
+    needle: &Value,
+    table: &[Vec<CellValue>],
+    column: f64,
+    approximate: bool,
+) -> Result<Value> {
+    let mut found = None;
+    for (i, row) in table.iter().enumerate() {
+        let Some(first) = row.first() else {
+            continue;
+        };
+        let ordering = compare(&from_cell(first)?, needle)?;
+        if ordering == Ordering::Equal {
+            found = Some(i);
+            break;
+        }
+        if approximate {
+            if ordering == Ordering::Greater {
+                break;
+            }
+            found = Some(i);
+        }
+    }
+    let row = &table[found.ok_or(FormulaError::NotAvailable)?];
+    let index = column as usize;
+    if column.fract() != 0.0 || index == 0 || index > row.len() {
+        return Err(FormulaError::Ref);
+    }
+    from_cell(&row[index - 1])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn eval_with(expr: &str, context: &[(&str, CellArgument)]) -> CellValue {
+        let context = context
+            .iter()
+            .map(|(name, arg)| (name.to_string(), arg.clone()))
+            .collect();
+        evaluate(expr, None, &context, &Functions::default())
+    }
+
+    fn int(i: i64) -> CellValue {
+        CellValue::Int(i)
+    }
+
+    #[test]
+    fn test_arithmetic_and_comparison() {
+        assert_eq!(eval_with("1 + 2 * 3 - 4 / 2", &[]), int(5));
+        assert_eq!(
+            eval_with("7 / 2", &[]),
+            CellValue::String("3.5".to_string())
+        );
+        assert_eq!(eval_with("0.1 + 0.2 = 0.3", &[]), int(0));
+        assert_eq!(eval_with("2 ^ 3 ^ 2", &[]), int(512));
+        assert_eq!(
+            eval_with("\"a\" & 1 + 1", &[]),
+            CellValue::String("a2".to_string())
+        );
+        assert_eq!(eval_with("3 >= 3 && \"b\" > \"a\"", &[]), int(1));
+    }
+
+    #[test]
+    fn test_compares_decimal_results_as_numbers() {
+        let half = CellArgument::Value(CellValue::String("1.5".to_string()));
+        let table = CellArgument::Matrix(vec![
+            vec![CellValue::String("0.5".to_string()), int(1)],
+            vec![CellValue::String("1.5".to_string()), int(2)],
+            vec![CellValue::String("10.5".to_string()), int(3)],
+        ]);
+        let context = [("A1", half), ("B1_C3", table)];
+        assert_eq!(eval_with("A1 < 2", &context), int(1));
+        assert_eq!(eval_with("A1 = 1.5", &context), int(1));
+        assert_eq!(eval_with("A1 = 3 / 2", &context), int(1));
+        assert_eq!(eval_with("A1 > 100", &context), int(0));
+        assert_eq!(eval_with("A1 >= 1", &context), int(1));
+        assert_eq!(eval_with("IF(A1 < 2, 10, 20)", &context), int(10));
+        assert_eq!(eval_with("VLOOKUP(1.5, B1_C3, 2, FALSE)", &context), int(2));
+        assert_eq!(eval_with("VLOOKUP(2, B1_C3, 2)", &context), int(2));
+        // Words still come after every number
+        assert_eq!(eval_with("\"n/a\" > 100", &context), int(1));
+    }
+
+    #[test]
+    fn test_errors() {
+        let error = |e: FormulaError| CellValue::Error(e.to_string());
+        assert_eq!(eval_with("1 / (2 - 2)", &[]), error(FormulaError::DivZero));
+        assert_eq!(
+            eval_with("nope(1)", &[]),
+            error(FormulaError::Name("NOPE()".to_string()))
+        );
+        assert_eq!(eval_with("#REF! + 1", &[]), error(FormulaError::Ref));
+        assert!(
+            matches!(eval_with("\"a\" * 2", &[]), CellValue::Error(e) if e.starts_with("#VALUE!"))
+        );
+
+        // Errors read from other cells name the cell they arose in
+        let column = CellArgument::Vector(vec![
+            int(1),
+            CellValue::Error(FormulaError::DivZero.to_string()),
+        ]);
+        let context = [("Sales!B1_B2", column)];
+        assert_eq!(
+            eval_with("SUM(Sales!B1_B2)", &context),
+            error(FormulaError::DivZero.passed_on_from("Sales!B2"))
+        );
+        assert_eq!(eval_with("IF(1, 2, Sales!B1_B2)", &context), int(2));
+    }
+
+    #[test]
+    fn test_functions_over_ranges() {
+        let column = CellArgument::Vector(vec![
+            int(3),
+            CellValue::String("1.5".to_string()),
+            CellValue::None,
+        ]);
+        let context = [("A1_A3", column)];
+        assert_eq!(
+            eval_with("SUM(A1_A3, 1)", &context),
+            CellValue::String("5.5".to_string())
+        );
+        assert_eq!(
+            eval_with("average(A1_A3)", &context),
+            CellValue::String("2.25".to_string())
+        );
+        assert_eq!(
+            eval_with("MIN(A1_A3)", &context),
+            CellValue::String("1.5".to_string())
+        );
+        assert_eq!(eval_with("MAX(A1_A3)", &context), int(3));
+        assert_eq!(eval_with("COUNT(A1_A3)", &context), int(2));
+        assert_eq!(eval_with("IF(0, 1 / 0, 2)", &context), int(2));
+    }
+
+    #[test]
+    fn test_volatile_functions() {
+        let CellValue::Int(now) = eval_with("NOW()", &[]) else {
+            panic!("NOW() isn't a whole number");
+        };
+        // Any time after this was written
+        assert!(now > 1_700_000_000);
+        for _ in 0..100 {
+            assert_eq!(eval_with("RAND() >= 0 && RAND() < 1", &[]), int(1));
+        }
+        assert_ne!(eval_with("RAND()", &[]), eval_with("RAND()", &[]));
+        assert!(
+            matches!(eval_with("NOW(1)", &[]), CellValue::Error(e) if e.starts_with("#VALUE!"))
+        );
+    }
+
+    #[test]
+    fn test_vlookup() {
+        let table = CellArgument::Matrix(vec![
+            vec![int(1), CellValue::String("one".to_string())],
+            vec![int(5), CellValue::String("five".to_string())],
+            vec![int(9), CellValue::String("nine".to_string())],
+        ]);
+        let context = [("A1_B3", table)];
+        assert_eq!(
+            eval_with("VLOOKUP(5, A1_B3, 2, FALSE)", &context),
+            CellValue::String("five".to_string())
+        );
+        assert_eq!(
+            eval_with("VLOOKUP(7, A1_B3, 2)", &context),
+            CellValue::String("five".to_string())
+        );
+        assert_eq!(
+            eval_with("VLOOKUP(7, A1_B3, 2, FALSE)", &context),
+            CellValue::Error(FormulaError::NotAvailable.to_string())
+        );
+        assert_eq!(
+            eval_with("VLOOKUP(1, A1_B3, 3)", &context),
+            CellValue::Error(FormulaError::Ref.to_string())
+        );
+    }
+}
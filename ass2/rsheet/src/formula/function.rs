@@ -0,0 +1,273 @@
+//! Functions defined by clients at runtime.
+
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::str::FromStr;
+use std::sync::Arc;
+
+use super::eval::{BUILTINS, SLOW, VOLATILE};
+use super::parse::parse;
+use super::Expr;
+use crate::reference::find_references;
+
+/// Every defined function, keyed by its upper-cased name
+pub type Functions = HashMap<String, Arc<Function>>;
+
+/// A function defined with `define name(a, b) = <formula>`. The body may only read its
+/// parameters, never cells, so a function's result depends on nothing but its arguments.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Function {
+    /// The name as it was written
+    name: String,
+    pub(super) params: Vec<String>,
+    pub(super) body: Expr,
+    /// The body as it was written, for logging the definition again
+    source: String,
+}
+
+impl Function {
+    /// The name calls are matched against, as function names are case-insensitive
+    pub fn key(&self) -> String {
+        self.name.to_ascii_uppercase()
+    }
+
+    /// The names of the functions the body calls
+    pub fn calls(&self) -> HashSet<String> {
+        calls(&self.body)
+    }
+}
+
+/// The upper-cased names of the functions a cell's formula calls
+pub fn called_functions(expr: &str) -> HashSet<String> {
+    parse(expr, None)
+        .map(|parsed| calls(&parsed))
+        .unwrap_or_default()
+}
+
+/// How a formula behaves over time, which recalculation is planned around
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Behaviour {
+    /// Its value can change without any cell it reads changing, as with `NOW()`
+    pub volatile: bool,
+    /// It holds up whichever evaluator runs it, as with `SLEEP_THEN`
+    pub slow: bool,
+}
+
+/// How a cell's formula behaves, looking through the defined functions it calls
+pub fn behaviour(expr: &str, functions: &Functions) -> Behaviour {
+    let mut called = called_functions(expr);
+    let mut unexpanded: Vec<String> = called.iter().cloned().collect();
+    while let Some(name) = unexpanded.pop() {
+        if let Some(function) = functions.get(&name) {
+            for callee in function.calls() {
+                if called.insert(callee.clone()) {
+                    unexpanded.push(callee);
+                }
+            }
+        }
+    }
+    let calls_any = |builtins: &[&str]| builtins.iter().any(|b| called.contains(*b));
+    Behaviour {
+        volatile: calls_any(&VOLATILE),
+        slow: calls_any(&SLOW),
+    }
+}
+
+fn calls(expr: &Expr) -> HashSet<String> {
+    let mut names = HashSet::new();
+    expr.walk(&mut |e| {
+        if let Expr::Call(name, _) = e {
+            names.insert(name.clone());
+        }
+    });
+    names
+}
+
+/// Whether `s` is an identifier that can't be mistaken for a cell or a constant
+fn is_name(s: &str) -> bool {
+    let mut chars = s.chars();
+    chars
+        .next()
+        .is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+        && find_references(s).is_empty()
+        && !["TRUE", "FALSE"].contains(&s.to_ascii_uppercase().as_str())
+}
+
+/// Parses what follows `define`, e.g. `tax(x) = x * 0.1`
+impl FromStr for Function {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let error = || format!("Error parsing function definition: {}", s.trim());
+        let (head, source) = s.split_once('=').ok_or_else(error)?;
+        let (name, params) = head
+            .trim()
+            .strip_suffix(')')
+            .and_then(|head| head.split_once('('))
+            .ok_or_else(error)?;
+        let name = name.trim();
+        let params: Vec<String> = match params.trim() {
+            "" => Vec::new(),
+            params => params.split(',').map(|p| p.trim().to_string()).collect(),
+        };
+        if !is_name(name) || !params.iter().all(|p| is_name(p)) {
+            return Err(error());
+        }
+
+        let key = name.to_ascii_uppercase();
+        if BUILTINS.contains(&key.as_str()) {
+            return Err(format!("Cannot redefine built-in function {}", key));
+        }
+        if let Some(param) = params
+            .iter()
+            .enumerate()
+            .find_map(|(i, param)| params[..i].contains(param).then_some(param))
+        {
+            return Err(format!("Parameter {} of {} appears twice", param, name));
+        }
+
+        let source = source.trim().to_string();
+        let body = parse(&source, None).map_err(|e| format!("Error in body of {}: {}", name, e))?;
+        let mut problem = None;
+        body.walk(&mut |e| match e {
+            Expr::Ref(cells) => {
+                problem.get_or_insert(format!("{} can't read cells such as {}", name, cells));
+            }
+            Expr::Name(unknown) if !params.contains(unknown) => {
+                problem.get_or_insert(format!("{} has no parameter named {}", name, unknown));
+            }
+            _ => {}
+        });
+        if let Some(problem) = problem {
+            return Err(problem);
+        }
+
+        Ok(Function {
+            name: name.to_string(),
+            params,
+            body,
+            source,
+        })
+    }
+}
+
+/// Writes the definition back out as the command that makes it
+impl fmt::Display for Function {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "define {}({}) = {}",
+            self.name,
+            self.params.join(", "),
+            self.source
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+
+    use rsheet_lib::cell_value::CellValue;
+
+    use super::super::evaluate;
+    use super::*;
+
+    fn define(functions: &mut Functions, definition: &str) {
+        let function: Function = definition.parse().unwrap();
+        functions.insert(function.key(), Arc::new(function));
+    }
+
+    #[test]
+    fn test_defined_functions_are_called_like_builtins() {
+        let mut functions = Functions::new();
+        define(&mut functions, "tax(x) = x * 0.1");
+        define(&mut functions, "gross(net, rate) = net + TAX(net) * rate");
+        define(&mut functions, "fact(n) = IF(n <= 1, 1, n * fact(n - 1))");
+        let eval = |expr| evaluate(expr, None, &HashMap::new(), &functions);
+
+        assert_eq!(eval("tax(50)"), CellValue::Int(5));
+        assert_eq!(eval("gross(100, 2)"), CellValue::Int(120));
+        assert_eq!(eval("fact(5)"), CellValue::Int(120));
+        assert!(matches!(eval("tax(1, 2)"), CellValue::Error(e) if e.starts_with("#VALUE!")));
+        assert!(matches!(eval("fact(1000)"), CellValue::Error(e) if e.starts_with("#VALUE!")));
+        assert_eq!(
+            functions["GROSS"].calls(),
+            HashSet::from(["TAX".to_string()])
+        );
+        assert_eq!(
+            functions["GROSS"].to_string(),
+            "define gross(net, rate) = net + TAX(net) * rate"
+        );
+    }
+
+    #[test]
+    fn test_deep_recursion_is_an_error_not_a_stack_overflow() {
+        let mut functions = Functions::new();
+        // Each call nests its recursion about as deep as a formula may
+        let padding = " + 0".repeat(240);
+        define(
+            &mut functions,
+            &format!("f(n) = IF(n <= 0, 0, f(n - 1)){}", padding),
+        );
+        define(&mut functions, "steps(n) = IF(n <= 0, 0, 1 + steps(n - 1))");
+        define(&mut functions, "chain(n) = chain(n - 1)");
+        define(&mut functions, "sums(n) = SUM(sums(n - 1))");
+        define(&mut functions, "found(n) = VLOOKUP(1, found(n - 1), 1)");
+        define(&mut functions, "most(n) = MAX(AVERAGE(-most(n - 1) & 1))");
+
+        // Evaluators get the default stack for a thread
+        let results = thread::Builder::new()
+            .stack_size(2 * 1024 * 1024)
+            .spawn(move || {
+                let eval = |expr| evaluate(expr, None, &HashMap::new(), &functions);
+                let recursions = ["f(63)", "chain(1)", "sums(1)", "found(1)", "most(1)"];
+                (recursions.map(eval), [eval("f(0)"), eval("steps(100)")])
+            })
+            .unwrap()
+            .join()
+            .unwrap();
+        for result in &results.0 {
+            assert!(matches!(result, CellValue::Error(e) if e.starts_with("#VALUE!")));
+        }
+        assert_eq!(results.1, [CellValue::Int(0), CellValue::Int(100)]);
+    }
+
+    #[test]
+    fn test_behaviour_follows_defined_functions() {
+        let mut functions = Functions::new();
+        define(&mut functions, "jitter(x) = x + rand()");
+        define(&mut functions, "wait(x) = sleep_then(100, x)");
+        define(&mut functions, "both(x) = jitter(wait(x))");
+        let behaviour = |expr| behaviour(expr, &functions);
+
+        assert_eq!(behaviour("A1 + 1"), Behaviour::default());
+        assert!(behaviour("NOW() - A1").volatile);
+        assert!(behaviour("jitter(1)").volatile && !behaviour("jitter(1)").slow);
+        assert!(!behaviour("wait(1)").volatile && behaviour("wait(1)").slow);
+        assert_eq!(
+            behaviour("both(1)"),
+            Behaviour {
+                volatile: true,
+                slow: true
+            }
+        );
+    }
+
+    #[test]
+    fn test_rejects_bad_definitions() {
+        for definition in [
+            "tax x = x",
+            "A1(x) = x",
+            "sum(x) = x",
+            "now() = 1",
+            "f(x, x) = x",
+            "f(x) = x + A1",
+            "f(x) = y",
+            "f(x) = (x",
+        ] {
+            assert!(definition.parse::<Function>().is_err(), "{}", definition);
+        }
+    }
+}
@@ -0,0 +1,199 @@
+//! The formula language cells are written in.
+//!
+//! A formula combines numbers, strings, cell references and function calls
+//! with arithmetic (`+ - * / % ^`), comparison (`= == != <> < <= > >=`),
+//! string concatenation (`&`) and logic (`&& || !`). Comparisons and logic
+//! produce 1 or 0. Numbers are computed as floats; whole results are stored
+//! as integers and fractional ones as decimal strings, which read back as
+//! numbers. Function names are case-insensitive.
+//!
+//! Clients can add functions of their own with `define`, e.g.
+//! `define tax(x) = x * 0.1`; see `Function`.
+//!
+//! A formula that fails stores a `FormulaError` in its cell. Reading that cell
+//! fails in turn with the same error, tagged with the cell it came from, so
+//! `B1 = A1 + 1` over `A1 = 1/0` holds `#DIV/0! (from A1)`.
+
+mod eval;
+mod function;
+mod parse;
+
+use std::convert::Infallible;
+use std::fmt;
+use std::str::FromStr;
+
+pub use eval::evaluate;
+pub use function::{behaviour, called_functions, Behaviour, Function, Functions};
+
+/// Why a formula has no value, shown as a spreadsheet-style error code
+#[derive(Debug, Clone, PartialEq)]
+pub enum FormulaError {
+    /// The formula can't be parsed
+    Parse(String),
+    /// An unknown function or name
+    Name(String),
+    /// An operand or argument of the wrong type
+    Value(String),
+    /// Division by zero
+    DivZero,
+    /// A reference to cells that were deleted
+    Ref,
+    /// A lookup found nothing
+    NotAvailable,
+    /// A result that isn't a finite number
+    Num,
+    /// The cell is part of a dependency cycle between these cells
+    Cycle(Vec<String>),
+    /// A cell the formula read holds an error; `cell` is where it arose
+    Upstream {
+        cell: String,
+        cause: Box<FormulaError>,
+    },
+}
+
+impl FormulaError {
+    /// The error a formula gets for reading `cell` when it holds this error. An error
+    /// already passed along keeps pointing at the cell it arose in.
+    pub fn passed_on_from(self, cell: &str) -> FormulaError {
+        match self {
+            FormulaError::Upstream { .. } => self,
+            cause => FormulaError::Upstream {
+                cell: cell.to_string(),
+                cause: Box::new(cause),
+            },
+        }
+    }
+}
+
+impl fmt::Display for FormulaError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FormulaError::Parse(detail) => write!(f, "#PARSE! {}", detail),
+            FormulaError::Name(name) => write!(f, "#NAME? {}", name),
+            FormulaError::Value(detail) => write!(f, "#VALUE! {}", detail),
+            FormulaError::DivZero => write!(f, "#DIV/0!"),
+            FormulaError::Ref => write!(f, "#REF!"),
+            FormulaError::NotAvailable => write!(f, "#N/A"),
+            FormulaError::Num => write!(f, "#NUM!"),
+            FormulaError::Cycle(members) => {
+                write!(
+                    f,
+                    "#CYCLE! circular dependency between {}",
+                    members.join(", ")
+                )
+            }
+            FormulaError::Upstream { cell, cause } => write!(f, "{} (from {})", cause, cell),
+        }
+    }
+}
+
+/// Reads back an error as stored in a cell. Anything unrecognised is kept as a `#VALUE!`.
+impl FromStr for FormulaError {
+    type Err = Infallible;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Some((cause, cell)) = s
+            .strip_suffix(')')
+            .and_then(|rest| rest.rsplit_once(" (from "))
+        {
+            return Ok(cause.parse::<FormulaError>()?.passed_on_from(cell));
+        }
+        let (code, detail) = s.split_once(' ').unwrap_or((s, ""));
+        Ok(match code {
+            "#PARSE!" => FormulaError::Parse(detail.to_string()),
+            "#NAME?" => FormulaError::Name(detail.to_string()),
+            "#VALUE!" => FormulaError::Value(detail.to_string()),
+            "#DIV/0!" => FormulaError::DivZero,
+            "#REF!" => FormulaError::Ref,
+            "#N/A" => FormulaError::NotAvailable,
+            "#NUM!" => FormulaError::Num,
+            "#CYCLE!" => FormulaError::Cycle(
+                detail
+                    .trim_start_matches("circular dependency between ")
+                    .split(", ")
+                    .map(str::to_string)
+                    .collect(),
+            ),
+            _ => FormulaError::Value(s.to_string()),
+        })
+    }
+}
+
+/// A parsed formula
+#[derive(Debug, Clone, PartialEq)]
+enum Expr {
+    Number(f64),
+    Text(String),
+    /// A cell or range, qualified with its sheet unless it is on the default sheet
+    Ref(String),
+    /// An error written into the formula itself, like `#REF!`
+    Error(FormulaError),
+    /// A name that isn't a cell reference
+    Name(String),
+    Unary(UnaryOp, Box<Expr>),
+    Binary(BinaryOp, Box<Expr>, Box<Expr>),
+    /// A function call, with the name in upper case
+    Call(String, Vec<Expr>),
+}
+
+impl Expr {
+    /// Calls `f` on this expression and every expression nested within it
+    fn walk(&self, f: &mut impl FnMut(&Expr)) {
+        f(self);
+        match self {
+            Expr::Unary(_, operand) => operand.walk(f),
+            Expr::Binary(_, lhs, rhs) => {
+                lhs.walk(f);
+                rhs.walk(f);
+            }
+            Expr::Call(_, args) => args.iter().for_each(|arg| arg.walk(f)),
+            _ => {}
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum UnaryOp {
+    Neg,
+    Plus,
+    Not,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum BinaryOp {
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Rem,
+    Pow,
+    Concat,
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    And,
+    Or,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_errors_read_back_as_stored() {
+        for error in [
+            FormulaError::DivZero,
+            FormulaError::Name("FOO()".to_string()),
+            FormulaError::Value("\"a\" is not a number".to_string()),
+            FormulaError::Cycle(vec!["A1".to_string(), "Sales!B2".to_string()]),
+            FormulaError::NotAvailable.passed_on_from("Sales!C3"),
+        ] {
+            assert_eq!(error.to_string().parse(), Ok(error));
+        }
+        let twice = FormulaError::Ref.passed_on_from("A1").passed_on_from("B1");
+        assert_eq!(twice.to_string(), "#REF! (from A1)");
+    }
+}
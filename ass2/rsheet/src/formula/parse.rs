@@ -0,0 +1,376 @@
+//! Tokenising and parsing formulas into expressions.
+
+use std::collections::HashMap;
+
+use super::{BinaryOp, Expr, FormulaError, UnaryOp};
+use crate::reference::find_references;
+use crate::structure::REF_ERROR;
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Number(f64),
+    Text(String),
+    Ref(String),
+    Ident(String),
+    RefError,
+    Op(&'static str),
+    LParen,
+    RParen,
+    Comma,
+}
+
+/// Operators, longest first so `<=` isn't read as `<` then `=`
+const OPERATORS: [&str; 18] = [
+    "==", "!=", "<>", "<=", ">=", "&&", "||", "+", "-", "*", "/", "%", "^", "&", "=", "<", ">", "!",
+];
+
+/// Splits a formula into tokens, qualifying cell references against `current_sheet`
+fn tokenise(expr: &str, current_sheet: Option<&str>) -> Result<Vec<Token>, FormulaError> {
+    // References are found exactly as dependency tracking finds them
+    let references: HashMap<usize, (usize, String)> = find_references(expr)
+        .into_iter()
+        .map(|r| (r.span.start, (r.span.end, r.qualified(current_sheet))))
+        .collect();
+
+    let bytes = expr.as_bytes();
+    let mut tokens = Vec::new();
+    let mut i = 0;
+    while i < bytes.len() {
+        let c = bytes[i];
+        if let Some((end, qualified)) = references.get(&i) {
+            tokens.push(Token::Ref(qualified.clone()));
+            i = *end;
+        } else if c.is_ascii_whitespace() {
+            i += 1;
+        } else if c.is_ascii_digit()
+            || (c == b'.' && bytes.get(i + 1).is_some_and(u8::is_ascii_digit))
+        {
+            let start = i;
+            while i < bytes.len() && (bytes[i].is_ascii_digit() || bytes[i] == b'.') {
+                i += 1;
+            }
+            let number = expr[start..i]
+                .parse()
+                .map_err(|_| FormulaError::Parse(format!("bad number {}", &expr[start..i])))?;
+            tokens.push(Token::Number(number));
+        } else if c.is_ascii_alphabetic() || c == b'_' {
+            let start = i;
+            while i < bytes.len() && (bytes[i].is_ascii_alphanumeric() || bytes[i] == b'_') {
+                i += 1;
+            }
+            tokens.push(Token::Ident(expr[start..i].to_string()));
+        } else if c == b'"' {
+            let (text, end) = read_string(expr, i)?;
+            tokens.push(Token::Text(text));
+            i = end;
+        } else if expr[i..].starts_with(REF_ERROR) {
+            tokens.push(Token::RefError);
+            i += REF_ERROR.len();
+        } else if c == b'(' {
+            tokens.push(Token::LParen);
+            i += 1;
+        } else if c == b')' {
+            tokens.push(Token::RParen);
+            i += 1;
+        } else if c == b',' {
+            tokens.push(Token::Comma);
+            i += 1;
+        } else if let Some(op) = OPERATORS.iter().find(|op| expr[i..].starts_with(**op)) {
+            tokens.push(Token::Op(op));
+            i += op.len();
+        } else {
+            let unexpected = expr[i..].chars().next().unwrap_or_default();
+            return Err(FormulaError::Parse(format!("unexpected '{}'", unexpected)));
+        }
+    }
+    Ok(tokens)
+}
+
+/// Reads the string literal starting at `start`, returning its text and where it ends
+fn read_string(expr: &str, start: usize) -> Result<(String, usize), FormulaError> {
+    let mut text = String::new();
+    let mut chars = expr[start + 1..].char_indices();
+    while let Some((offset, c)) = chars.next() {
+        match c {
+            '"' => return Ok((text, start + 1 + offset + 1)),
+            '\\' => match chars.next().map(|(_, escaped)| escaped) {
+                Some('n') => text.push('\n'),
+                Some('r') => text.push('\r'),
+                Some('t') => text.push('\t'),
+                Some(escaped) => text.push(escaped),
+                None => break,
+            },
+            c => text.push(c),
+        }
+    }
+    Err(FormulaError::Parse("unterminated string".to_string()))
+}
+
+/// Parses a formula written in a cell on `current_sheet`
+pub(super) fn parse(expr: &str, current_sheet: Option<&str>) -> Result<Expr, FormulaError> {
+    let tokens = tokenise(expr, current_sheet)?;
+    let mut parser = Parser {
+        tokens,
+        pos: 0,
+        nesting: 0,
+    };
+    let (parsed, _) = parser.binary(0)?;
+    match parser.peek() {
+        None => Ok(parsed),
+        Some(token) => Err(FormulaError::Parse(format!("unexpected {:?}", token))),
+    }
+}
+
+/// Binary operators from loosest to tightest binding; all are left-associative except `^`
+const PRECEDENCE: [&[(&str, BinaryOp)]; 7] = [
+    &[("||", BinaryOp::Or)],
+    &[("&&", BinaryOp::And)],
+    &[
+        ("=", BinaryOp::Eq),
+        ("==", BinaryOp::Eq),
+        ("!=", BinaryOp::Ne),
+        ("<>", BinaryOp::Ne),
+        ("<", BinaryOp::Lt),
+        ("<=", BinaryOp::Le),
+        (">", BinaryOp::Gt),
+        (">=", BinaryOp::Ge),
+    ],
+    &[("&", BinaryOp::Concat)],
+    &[("+", BinaryOp::Add), ("-", BinaryOp::Sub)],
+    &[
+        ("*", BinaryOp::Mul),
+        ("/", BinaryOp::Div),
+        ("%", BinaryOp::Rem),
+    ],
+    &[("^", BinaryOp::Pow)],
+];
+
+/// Deepest brackets, calls and prefix operators may nest; the parser recurses
+/// through every precedence level for each one
+const MAX_NESTING: usize = 64;
+
+/// Deepest a parsed expression may be, counting every operator and call. Evaluating
+/// and even dropping an expression recurse once per level, so without a limit one
+/// long formula could overflow the stack.
+const MAX_DEPTH: usize = 256;
+
+/// A parsed expression along with how deeply it nests
+type Parsed = (Expr, usize);
+
+struct Parser {
+    tokens: Vec<Token>,
+    pos: usize,
+    /// How many operands are being parsed within one another
+    nesting: usize,
+}
+
+fn too_deep(limit: usize) -> FormulaError {
+    FormulaError::Parse(format!("formula nests more than {} deep", limit))
+}
+
+/// Wraps one more level around an expression `depth` deep
+fn nest(expr: Expr, depth: usize) -> Result<Parsed, FormulaError> {
+    if depth >= MAX_DEPTH {
+        return Err(too_deep(MAX_DEPTH));
+    }
+    Ok((expr, depth + 1))
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos)
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.pos).cloned();
+        self.pos += 1;
+        token
+    }
+
+    fn expect(&mut self, expected: Token) -> Result<(), FormulaError> {
+        match self.next() {
+            Some(token) if token == expected => Ok(()),
+            Some(token) => Err(FormulaError::Parse(format!(
+                "expected {:?}, found {:?}",
+                expected, token
+            ))),
+            None => Err(FormulaError::Parse(format!("expected {:?}", expected))),
+        }
+    }
+
+    /// Parses operators binding at least as tightly as `PRECEDENCE[level]`
+    fn binary(&mut self, level: usize) -> Result<Parsed, FormulaError> {
+        let Some(operators) = PRECEDENCE.get(level) else {
+            return self.prefix();
+        };
+        let (mut lhs, mut depth) = self.binary(level + 1)?;
+        while let Some(Token::Op(symbol)) = self.peek() {
+            let Some(&(_, op)) = operators.iter().find(|(s, _)| s == symbol) else {
+                break;
+            };
+            self.pos += 1;
+            // `^` is right-associative: 2^3^2 is 2^(3^2)
+            let (rhs, rhs_depth) = if op == BinaryOp::Pow {
+                self.descend(|parser| parser.binary(level))?
+            } else {
+                self.binary(level + 1)?
+            };
+            (lhs, depth) = nest(
+                Expr::Binary(op, Box::new(lhs), Box::new(rhs)),
+                depth.max(rhs_depth),
+            )?;
+        }
+        Ok((lhs, depth))
+    }
+
+    /// Parses an operand nested one level further in, which is how every bracket,
+    /// call, prefix operator and `^` recurses
+    fn descend(
+        &mut self,
+        parse: impl FnOnce(&mut Self) -> Result<Parsed, FormulaError>,
+    ) -> Result<Parsed, FormulaError> {
+        self.nesting += 1;
+        if self.nesting > MAX_NESTING {
+            return Err(too_deep(MAX_NESTING));
+        }
+        let parsed = parse(self);
+        self.nesting -= 1;
+        parsed
+    }
+
+    fn prefix(&mut self) -> Result<Parsed, FormulaError> {
+        let op = match self.peek() {
+            Some(Token::Op("-")) => UnaryOp::Neg,
+            Some(Token::Op("+")) => UnaryOp::Plus,
+            Some(Token::Op("!")) => UnaryOp::Not,
+            _ => return self.descend(Self::primary),
+        };
+        self.pos += 1;
+        let (operand, depth) = self.descend(Self::prefix)?;
+        nest(Expr::Unary(op, Box::new(operand)), depth)
+    }
+
+    fn primary(&mut self) -> Result<Parsed, FormulaError> {
+        let leaf = |expr| Ok((expr, 1));
+        match self.next() {
+            Some(Token::Number(n)) => leaf(Expr::Number(n)),
+            Some(Token::Text(s)) => leaf(Expr::Text(s)),
+            Some(Token::Ref(r)) => leaf(Expr::Ref(r)),
+            Some(Token::RefError) => leaf(Expr::Error(FormulaError::Ref)),
+            Some(Token::LParen) => {
+                let inner = self.binary(0)?;
+                self.expect(Token::RParen)?;
+                Ok(inner)
+            }
+            Some(Token::Ident(name)) if self.peek() == Some(&Token::LParen) => {
+                self.pos += 1;
+                let mut args = Vec::new();
+                let mut depth = 0;
+                if self.peek() == Some(&Token::RParen) {
+                    self.pos += 1;
+                } else {
+                    loop {
+                        let (arg, arg_depth) = self.binary(0)?;
+                        args.push(arg);
+                        depth = depth.max(arg_depth);
+                        match self.next() {
+                            Some(Token::Comma) => continue,
+                            Some(Token::RParen) => break,
+                            _ => {
+                                return Err(FormulaError::Parse(format!(
+                                    "unclosed call to {}",
+                                    name
+                                )))
+                            }
+                        }
+                    }
+                }
+                nest(Expr::Call(name.to_ascii_uppercase(), args), depth)
+            }
+            Some(Token::Ident(name)) => match name.to_ascii_uppercase().as_str() {
+                "TRUE" => leaf(Expr::Number(1.0)),
+                "FALSE" => leaf(Expr::Number(0.0)),
+                _ => leaf(Expr::Name(name)),
+            },
+            Some(token) => Err(FormulaError::Parse(format!("unexpected {:?}", token))),
+            None => Err(FormulaError::Parse("unexpected end of formula".to_string())),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn num(n: f64) -> Box<Expr> {
+        Box::new(Expr::Number(n))
+    }
+
+    #[test]
+    fn test_precedence() {
+        assert_eq!(
+            parse("1 + 2 * 3", None),
+            Ok(Expr::Binary(
+                BinaryOp::Add,
+                num(1.0),
+                Box::new(Expr::Binary(BinaryOp::Mul, num(2.0), num(3.0)))
+            ))
+        );
+        assert_eq!(
+            parse("-2 ^ 2", None),
+            Ok(Expr::Binary(
+                BinaryOp::Pow,
+                Box::new(Expr::Unary(UnaryOp::Neg, num(2.0))),
+                num(2.0)
+            ))
+        );
+    }
+
+    #[test]
+    fn test_references_calls_and_strings() {
+        assert_eq!(
+            parse(r#"sum(A1_A3, Costs!$B$1) & "a\"b""#, Some("Sales")),
+            Ok(Expr::Binary(
+                BinaryOp::Concat,
+                Box::new(Expr::Call(
+                    "SUM".to_string(),
+                    vec![
+                        Expr::Ref("Sales!A1_A3".to_string()),
+                        Expr::Ref("Costs!B1".to_string())
+                    ]
+                )),
+                Box::new(Expr::Text("a\"b".to_string()))
+            ))
+        );
+        assert!(matches!(parse("1 +", None), Err(FormulaError::Parse(_))));
+        assert!(matches!(parse("(1", None), Err(FormulaError::Parse(_))));
+    }
+
+    #[test]
+    fn test_rejects_formulas_nested_too_deep() {
+        let nested = |depth: usize, open: &str, inner: &str, close: &str| {
+            format!("{}{}{}", open.repeat(depth), inner, close.repeat(depth))
+        };
+        let nesting = MAX_NESTING - 1;
+        assert!(parse(&nested(nesting, "(", "1", ")"), None).is_ok());
+        assert!(parse(&nested(nesting, "-", "1", ""), None).is_ok());
+        assert!(parse(&nested(MAX_DEPTH - 1, "1 + ", "1", ""), None).is_ok());
+
+        let huge = 100_000;
+        for (formula, limit) in [
+            (nested(huge, "(", "1", ")"), MAX_NESTING),
+            (nested(huge, "-", "1", ""), MAX_NESTING),
+            (nested(huge, "sum(", "1", ")"), MAX_NESTING),
+            (nested(huge, "2 ^ ", "1", ""), MAX_NESTING),
+            (nested(huge, "1 + ", "1", ""), MAX_DEPTH),
+            (nested(huge, "", "1", " * 1"), MAX_DEPTH),
+        ] {
+            assert_eq!(
+                parse(&formula, None),
+                Err(too_deep(limit)),
+                "{:.20}",
+                formula
+            );
+        }
+    }
+}
@@ -0,0 +1,238 @@
+//! Dependency graph between cells.
+//!
+//! Forward edges (what a cell reads) and reverse edges (who reads a cell) are
+//! both updated whenever a cell's expression changes, so finding the cells to
+//! recalculate never requires a scan of the whole sheet.
+
+use std::collections::{HashMap, HashSet};
+
+use crate::command::CellRef;
+
+/// A strongly connected group of cells in recalculation order
+#[derive(Debug, PartialEq, Eq)]
+pub struct Component {
+    /// The cells in the group, sorted by sheet, column and row
+    pub cells: Vec<CellRef>,
+    /// Whether the cells depend on each other (or a lone cell on itself)
+    pub cyclic: bool,
+}
+
+#[derive(Debug, Default)]
+pub struct DepGraph {
+    /// Cells each cell's expression reads from
+    precedents: HashMap<CellRef, HashSet<CellRef>>,
+    /// Cells whose expressions read each cell
+    dependents: HashMap<CellRef, HashSet<CellRef>>,
+}
+
+impl DepGraph {
+    /// Replaces the cells `cell` reads from, updating the reverse edges to match
+    pub fn set_precedents(
+        &mut self,
+        cell: &CellRef,
+        precedents: impl IntoIterator<Item = CellRef>,
+    ) {
+        let new: HashSet<CellRef> = precedents.into_iter().collect();
+        let old = self.precedents.remove(cell).unwrap_or_default();
+
+        for gone in old.difference(&new) {
+            if let Some(readers) = self.dependents.get_mut(gone) {
+                readers.remove(cell);
+                if readers.is_empty() {
+                    self.dependents.remove(gone);
+                }
+            }
+        }
+        for added in new.difference(&old) {
+            self.dependents
+                .entry(added.clone())
+                .or_default()
+                .insert(cell.clone());
+        }
+
+        if !new.is_empty() {
+            self.precedents.insert(cell.clone(), new);
+        }
+    }
+
+    /// The cells `cell` reads from
+    pub fn precedents(&self, cell: &CellRef) -> impl Iterator<Item = &CellRef> {
+        self.precedents.get(cell).into_iter().flatten()
+    }
+
+    /// The cells that read `cell`
+    pub fn dependents(&self, cell: &CellRef) -> impl Iterator<Item = &CellRef> {
+        self.dependents.get(cell).into_iter().flatten()
+    }
+
+    /// Groups the changed cells `starts` and everything that transitively depends on them
+    /// into strongly connected components, ordered so each component comes after
+    /// everything it reads. A cell downstream of several changed cells appears only once,
+    /// after all of them. With a single start, its component always comes first.
+    pub fn recalc_order<'a>(&'a self, starts: &'a [CellRef]) -> Vec<Component> {
+        struct Frame<'a> {
+            cell: &'a CellRef,
+            children: Vec<&'a CellRef>,
+            next: usize,
+        }
+
+        // Iterative Tarjan over the reverse edges, so long chains can't overflow the stack
+        let mut next_index = 0;
+        let mut index: HashMap<&CellRef, usize> = HashMap::new();
+        let mut lowlink: HashMap<&CellRef, usize> = HashMap::new();
+        let mut stack: Vec<&CellRef> = Vec::new();
+        let mut on_stack: HashSet<&CellRef> = HashSet::new();
+        let mut components = Vec::new();
+
+        for start in starts.iter().rev() {
+            if index.contains_key(start) {
+                continue;
+            }
+            index.insert(start, next_index);
+            lowlink.insert(start, next_index);
+            next_index += 1;
+            stack.push(start);
+            on_stack.insert(start);
+            let mut work = vec![Frame {
+                cell: start,
+                children: self.dependents(start).collect(),
+                next: 0,
+            }];
+
+            while let Some(frame) = work.last_mut() {
+                if let Some(&child) = frame.children.get(frame.next) {
+                    frame.next += 1;
+                    let cell = frame.cell;
+                    if !index.contains_key(child) {
+                        index.insert(child, next_index);
+                        lowlink.insert(child, next_index);
+                        next_index += 1;
+                        stack.push(child);
+                        on_stack.insert(child);
+                        work.push(Frame {
+                            cell: child,
+                            children: self.dependents(child).collect(),
+                            next: 0,
+                        });
+                    } else if on_stack.contains(child) {
+                        let low = lowlink[cell].min(index[child]);
+                        lowlink.insert(cell, low);
+                    }
+                    continue;
+                }
+
+                let cell = frame.cell;
+                work.pop();
+                let low = lowlink[cell];
+                if let Some(parent) = work.last() {
+                    let parent_low = lowlink[parent.cell].min(low);
+                    lowlink.insert(parent.cell, parent_low);
+                }
+                if low == index[cell] {
+                    let mut cells = Vec::new();
+                    while let Some(member) = stack.pop() {
+                        on_stack.remove(member);
+                        cells.push(member.clone());
+                        if member == cell {
+                            break;
+                        }
+                    }
+                    cells.sort();
+                    let cyclic = cells.len() > 1 || self.dependents(cell).any(|dep| dep == cell);
+                    components.push(Component { cells, cyclic });
+                }
+            }
+        }
+
+        // Tarjan emits components after everything reachable from them, i.e. readers first
+        components.reverse();
+        components
+    }
+}
+
+impl AsRef<DepGraph> for DepGraph {
+    fn as_ref(&self) -> &DepGraph {
+        self
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(key: &str) -> CellRef {
+        key.parse().unwrap()
+    }
+
+    fn graph(edges: &[(&str, &[&str])]) -> DepGraph {
+        let mut graph = DepGraph::default();
+        for (cell, precedents) in edges {
+            graph.set_precedents(&at(cell), precedents.iter().map(|p| at(p)));
+        }
+        graph
+    }
+
+    fn keys<'a>(cells: impl IntoIterator<Item = &'a CellRef>) -> Vec<String> {
+        cells.into_iter().map(CellRef::key).collect()
+    }
+
+    fn order(graph: &DepGraph, starts: &[&str]) -> Vec<Component> {
+        let starts: Vec<CellRef> = starts.iter().map(|start| at(start)).collect();
+        graph.recalc_order(&starts)
+    }
+
+    fn cells(components: &[Component]) -> Vec<Vec<String>> {
+        components.iter().map(|c| keys(&c.cells)).collect()
+    }
+
+    #[test]
+    fn test_updates_reverse_edges() {
+        let mut graph = graph(&[("B1", &["A1"]), ("C1", &["A1", "B1"])]);
+        graph.set_precedents(&at("C1"), vec![at("B1")]);
+
+        assert_eq!(keys(graph.dependents(&at("A1"))), vec!["B1"]);
+        assert_eq!(keys(graph.dependents(&at("B1"))), vec!["C1"]);
+        assert_eq!(keys(graph.precedents(&at("C1"))), vec!["B1"]);
+    }
+
+    #[test]
+    fn test_recalc_order_visits_each_cell_once() {
+        // A1 feeds B1 and C1, which both feed D1
+        let graph = graph(&[("B1", &["A1"]), ("C1", &["A1"]), ("D1", &["B1", "C1"])]);
+        let order = order(&graph, &["A1"]);
+
+        assert_eq!(order.len(), 4);
+        assert!(order.iter().all(|c| !c.cyclic));
+        let position = |cell: &str| order.iter().position(|c| c.cells == [at(cell)]).unwrap();
+        assert_eq!(position("A1"), 0);
+        assert!(position("B1") < position("D1"));
+        assert!(position("C1") < position("D1"));
+    }
+
+    #[test]
+    fn test_recalc_order_groups_cycles() {
+        let graph = graph(&[
+            ("A1", &["B1"]),
+            ("B1", &["A1"]),
+            ("C1", &["B1"]),
+            ("D1", &["D1"]),
+        ]);
+
+        let components = order(&graph, &["A1"]);
+        assert_eq!(cells(&components), vec![vec!["A1", "B1"], vec!["C1"]]);
+        assert!(components[0].cyclic);
+        assert!(!components[1].cyclic);
+
+        assert!(order(&graph, &["D1"])[0].cyclic);
+    }
+
+    #[test]
+    fn test_recalc_order_from_several_cells() {
+        // A1 -> B1 -> C1 -> D1, with both A1 and C1 changed
+        let graph = graph(&[("B1", &["A1"]), ("C1", &["B1"]), ("D1", &["C1"])]);
+        assert_eq!(
+            cells(&order(&graph, &["C1", "A1"])),
+            vec![vec!["A1"], vec!["B1"], vec!["C1"], vec!["D1"]]
+        );
+    }
+}
@@ -0,0 +1,164 @@
+//! Per-cell edit history, and the undo/redo stacks built from it.
+//!
+//! Every change to a cell's expression is kept as a new version of that cell.
+//! Edits made by clients can be undone either by the connection that made
+//! them or by anyone (`global`), most recent first. Inserting or deleting rows
+//! and columns can't be undone, and forgets every edit made before it. History
+//! lives in memory only; a restored sheet starts with a clean slate.
+
+use std::collections::HashMap;
+
+use crate::command::CellRef;
+
+/// One change to a cell's expression; `None` means the cell had no expression
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Edit {
+    /// Id of the connection that made the edit
+    pub conn: String,
+    pub cell: CellRef,
+    pub before: Option<String>,
+    pub after: Option<String>,
+}
+
+#[derive(Debug, Default)]
+pub struct History {
+    /// Every expression each cell has had, oldest first
+    versions: HashMap<CellRef, Vec<Option<String>>>,
+    /// Edits that can be undone, oldest first
+    done: Vec<Edit>,
+    /// Edits that can be redone, most recently undone last
+    undone: Vec<Edit>,
+}
+
+impl History {
+    /// Records an edit a client just made, forgetting anything its connection could redo
+    pub fn record(&mut self, edit: Edit) {
+        self.undone.retain(|undone| undone.conn != edit.conn);
+        self.push_version(&edit.cell, edit.after.clone());
+        self.done.push(edit);
+    }
+
+    /// The expressions a cell has had, oldest first
+    pub fn versions(&self, cell: &CellRef) -> &[Option<String>] {
+        self.versions
+            .get(cell)
+            .map(Vec::as_slice)
+            .unwrap_or_default()
+    }
+
+    /// Undoes the latest edit made by `conn`, or by anyone if `conn` is `None`, by
+    /// handing it to `restore`. Returns whether there was anything to undo.
+    pub fn undo(
+        &mut self,
+        conn: Option<&str>,
+        restore: impl FnOnce(&Edit) -> Result<(), String>,
+    ) -> Result<bool, String> {
+        let Some(index) = latest(&self.done, conn) else {
+            return Ok(false);
+        };
+        restore(&self.done[index])?;
+        let edit = self.done.remove(index);
+        self.push_version(&edit.cell, edit.before.clone());
+        self.undone.push(edit);
+        Ok(true)
+    }
+
+    /// Redoes the latest edit undone for `conn`, or for anyone if `conn` is `None`, by
+    /// handing it to `reapply`. Returns whether there was anything to redo.
+    pub fn redo(
+        &mut self,
+        conn: Option<&str>,
+        reapply: impl FnOnce(&Edit) -> Result<(), String>,
+    ) -> Result<bool, String> {
+        let Some(index) = latest(&self.undone, conn) else {
+            return Ok(false);
+        };
+        reapply(&self.undone[index])?;
+        let edit = self.undone.remove(index);
+        self.push_version(&edit.cell, edit.after.clone());
+        self.done.push(edit);
+        Ok(true)
+    }
+
+    /// Follows cells moved by a structural edit. Edits made before it can no longer be
+    /// undone or redone, since their expressions refer to cells by their old positions.
+    pub fn restructure(&mut self, move_cell: impl Fn(&CellRef) -> Option<CellRef>) {
+        self.versions = std::mem::take(&mut self.versions)
+            .into_iter()
+            .filter_map(|(cell, versions)| Some((move_cell(&cell)?, versions)))
+            .collect();
+        self.done.clear();
+        self.undone.clear();
+    }
+
+    fn push_version(&mut self, cell: &CellRef, expr: Option<String>) {
+        self.versions.entry(cell.clone()).or_default().push(expr);
+    }
+}
+
+/// Position of the most recent edit in `edits` made by `conn` (any connection if `None`)
+fn latest(edits: &[Edit], conn: Option<&str>) -> Option<usize> {
+    edits
+        .iter()
+        .rposition(|edit| conn.is_none_or(|conn| edit.conn == conn))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn edit(conn: &str, key: &str, before: Option<&str>, after: &str) -> Edit {
+        Edit {
+            conn: conn.to_string(),
+            cell: key.parse().unwrap(),
+            before: before.map(str::to_string),
+            after: Some(after.to_string()),
+        }
+    }
+
+    #[test]
+    fn test_undo_is_per_connection_unless_global() {
+        let mut history = History::default();
+        history.record(edit("a", "A1", None, "1"));
+        history.record(edit("b", "A1", Some("1"), "2"));
+
+        let mut restored = Vec::new();
+        let mut restore = |edit: &Edit| {
+            restored.push(edit.before.clone());
+            Ok(())
+        };
+        assert_eq!(history.undo(Some("a"), &mut restore), Ok(true));
+        assert_eq!(history.undo(Some("a"), &mut restore), Ok(false));
+        assert_eq!(history.undo(None, &mut restore), Ok(true));
+        assert_eq!(restored, vec![None, Some("1".to_string())]);
+
+        let versions: Vec<_> = history
+            .versions(&"A1".parse().unwrap())
+            .iter()
+            .map(Option::as_deref)
+            .collect();
+        assert_eq!(versions, vec![Some("1"), Some("2"), None, Some("1")]);
+    }
+
+    #[test]
+    fn test_new_edit_clears_only_its_connections_redo() {
+        let mut history = History::default();
+        history.record(edit("a", "A1", None, "1"));
+        history.record(edit("b", "B1", None, "2"));
+        history.undo(None, |_| Ok(())).unwrap();
+        history.undo(None, |_| Ok(())).unwrap();
+
+        history.record(edit("a", "C1", None, "3"));
+        assert_eq!(history.redo(Some("a"), |_| Ok(())), Ok(false));
+        assert_eq!(history.redo(Some("b"), |_| Ok(())), Ok(true));
+    }
+
+    #[test]
+    fn test_failed_undo_keeps_the_edit() {
+        let mut history = History::default();
+        history.record(edit("a", "A1", None, "1"));
+        let failed = history.undo(None, |_| Err("disk full".to_string()));
+        assert_eq!(failed, Err("disk full".to_string()));
+        assert_eq!(history.undo(None, |_| Ok(())), Ok(true));
+    }
+}
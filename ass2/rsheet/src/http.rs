@@ -0,0 +1,664 @@
+//! A small REST front end that turns HTTP requests into ordinary sheet commands.
+//!
+//! - `GET /cells/A1` runs `get A1` and answers `{"cell": "A1", "value": 5}`
+//! - `PUT /cells/A1` runs `set A1 <body>` and answers `204 No Content`
+//! - `GET /range/A1_C3` runs `get A1_C3` and answers its row-major JSON array
+//! - `GET /metrics` runs `stats` and answers in Prometheus' text format
+//!
+//! Cells and ranges may name a sheet, e.g. `/cells/Sales!B2`. When the server
+//! has users, requests log in with HTTP Basic authentication.
+//!
+//! Errors are answered as `{"error": "..."}`: `401` without a valid login, `403`
+//! for a change the user may not make, `400` for anything else wrong with the
+//! request, and `503` when cells take too long to settle.
+//!
+//! Each HTTP request becomes its own short-lived connection, so the server
+//! handles it exactly like a line-protocol client sending the same commands.
+//! Requests are read on threads of their own, so one slow client can't hold up
+//! the rest, and their request line and headers are capped in size.
+
+use std::collections::VecDeque;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::thread;
+use std::time::Duration;
+
+use rsheet_lib::cell_value::CellValue;
+use rsheet_lib::connect::{
+    Connection, Manager, ReadMessageResult, Reader, ReaderWriter, WriteMessageResult, Writer,
+};
+use rsheet_lib::replies::Reply;
+use serde_json::{json, Value};
+
+use crate::auth::{is_refusal, BAD_LOGIN, NOT_LOGGED_IN};
+use crate::cell_id_to_string;
+use crate::command::{CellRange, CellRef, MAX_RANGE_CELLS};
+use crate::formula::FormulaError;
+use crate::scheduler::TIMED_OUT;
+use crate::session::FOLLOWING;
+
+/// Largest request body accepted for `PUT /cells/...`
+const MAX_BODY_BYTES: usize = 64 * 1024;
+/// Content type of the Prometheus text exposition format
+const PROMETHEUS_TYPE: &str = "text/plain; version=0.0.4";
+/// How long a client may take to send each part of its request
+const READ_TIMEOUT: Duration = Duration::from_secs(5);
+/// Longest request line or header line accepted, in bytes
+const MAX_LINE_BYTES: usize = 8 * 1024;
+/// Most headers accepted in one request
+const MAX_HEADERS: usize = 100;
+
+pub struct HttpReaderWriter;
+impl ReaderWriter for HttpReaderWriter {
+    type Reader = HttpReader;
+    type Writer = HttpWriter;
+}
+
+/// Accepts HTTP requests, handing each well-formed one to the server as a connection
+pub struct HttpManager {
+    /// Requests read so far, until the listener fails
+    requests: Receiver<(HttpReader, HttpWriter)>,
+}
+
+/// Replays the sheet commands an HTTP request was translated into
+pub struct HttpReader {
+    commands: VecDeque<String>,
+    id: String,
+}
+
+/// Collects the replies to a request and sends them as one HTTP response when dropped
+pub struct HttpWriter {
+    socket: TcpStream,
+    id: String,
+    shape: Shape,
+    replies: Vec<Reply>,
+}
+
+/// How the replies to a request are assembled into a response body
+#[derive(Debug, PartialEq, Eq)]
+enum Shape {
+    /// `GET /cells/...`: a single cell
+    Cell,
+    /// `GET /range/...`: the rows of a range, already encoded as JSON
+    Range,
+    /// `PUT /cells/...`: no reply unless something went wrong
+    Update,
+    /// `GET /metrics`: one counter per reply
+    Metrics,
+}
+
+/// A request that could not be turned into sheet commands
+#[derive(Debug)]
+struct Rejection {
+    status: &'static str,
+    message: String,
+}
+
+impl Rejection {
+    fn new(status: &'static str, message: impl Into<String>) -> Self {
+        Rejection {
+            status,
+            message: message.into(),
+        }
+    }
+}
+
+impl HttpManager {
+    pub fn bind(addr: SocketAddr) -> std::io::Result<Self> {
+        let listener = TcpListener::bind(addr)?;
+        let (sender, requests) = mpsc::channel();
+        thread::spawn(move || accept_requests(listener, sender));
+        Ok(HttpManager { requests })
+    }
+}
+
+impl Manager for HttpManager {
+    type ReaderWriter = HttpReaderWriter;
+
+    fn accept_new_connection(&mut self) -> Connection<HttpReader, HttpWriter> {
+        match self.requests.recv() {
+            Ok((reader, writer)) => Connection::NewConnection { reader, writer },
+            Err(_) => Connection::NoMoreConnections,
+        }
+    }
+}
+
+/// Accepts connections until the listener fails, reading each one's request on a
+/// thread of its own
+fn accept_requests(listener: TcpListener, requests: Sender<(HttpReader, HttpWriter)>) {
+    while let Ok((socket, addr)) = listener.accept() {
+        let requests = requests.clone();
+        thread::spawn(move || {
+            if let Some(request) = read_request(socket, addr) {
+                let _ = requests.send(request);
+            }
+        });
+    }
+}
+
+/// Reads and translates one request, answering it at once if it is malformed so it
+/// never reaches the sheet
+fn read_request(socket: TcpStream, addr: SocketAddr) -> Option<(HttpReader, HttpWriter)> {
+    let read_half = socket.try_clone().ok()?;
+    let _ = read_half.set_read_timeout(Some(READ_TIMEOUT));
+    match translate(&mut BufReader::new(read_half)) {
+        Ok((commands, shape)) => {
+            let id = addr.to_string();
+            let reader = HttpReader {
+                commands: commands.into(),
+                id: id.clone(),
+            };
+            let writer = HttpWriter {
+                socket,
+                id,
+                shape,
+                replies: Vec::new(),
+            };
+            Some((reader, writer))
+        }
+        Err(rejection) => {
+            let body = json!({ "error": rejection.message });
+            respond(Ok(socket), rejection.status, Some(body));
+            None
+        }
+    }
+}
+
+impl Reader for HttpReader {
+    fn read_message(&mut self) -> ReadMessageResult {
+        match self.commands.pop_front() {
+            Some(command) => ReadMessageResult::Message(command),
+            None => ReadMessageResult::ConnectionClosed,
+        }
+    }
+
+    fn id(&self) -> String {
+        self.id.clone()
+    }
+}
+
+impl Writer for HttpWriter {
+    fn write_message(&mut self, message: Reply) -> WriteMessageResult {
+        self.replies.push(message);
+        WriteMessageResult::Ok
+    }
+
+    fn id(&self) -> String {
+        self.id.clone()
+    }
+}
+
+impl Drop for HttpWriter {
+    fn drop(&mut self) {
+        let replies = std::mem::take(&mut self.replies);
+        let socket = self.socket.try_clone();
+        match response(&self.shape, replies) {
+            (status, Body::Json(body)) => respond(socket, status, Some(body)),
+            (status, Body::Empty) => respond(socket, status, None),
+            (status, Body::Metrics(text)) => respond_with(socket, status, PROMETHEUS_TYPE, &text),
+        }
+    }
+}
+
+/// What a response carries
+#[derive(Debug, PartialEq)]
+enum Body {
+    Json(Value),
+    Empty,
+    Metrics(String),
+}
+
+/// The status and body answering a request of `shape` with `replies`
+fn response(shape: &Shape, replies: Vec<Reply>) -> (&'static str, Body) {
+    let error = |e: &str| Body::Json(json!({ "error": e }));
+    let no_reply = ("500 Internal Server Error", error("no reply"));
+    match (shape, replies.first()) {
+        // A failed login fails everything after it, so it answers the whole request
+        (_, Some(Reply::Error(e))) if e == BAD_LOGIN || e == NOT_LOGGED_IN => {
+            ("401 Unauthorized", error(e))
+        }
+        (Shape::Cell, Some(Reply::Value(cell, value))) => (
+            "200 OK",
+            Body::Json(json!({ "cell": cell, "value": value_to_json(value.clone()) })),
+        ),
+        // The cell can't be evaluated, which is as much an answer as its value
+        (Shape::Cell, Some(Reply::Error(e)))
+            if matches!(
+                e.parse(),
+                Ok(FormulaError::Upstream { .. } | FormulaError::Cycle(_))
+            ) =>
+        {
+            ("200 OK", error(e))
+        }
+        (Shape::Range, Some(Reply::Value(_, CellValue::String(rows)))) => {
+            match serde_json::from_str(rows) {
+                Ok(rows) => ("200 OK", Body::Json(rows)),
+                Err(_) => ("500 Internal Server Error", error("bad range")),
+            }
+        }
+        (Shape::Metrics, _) => ("200 OK", Body::Metrics(prometheus_text(replies))),
+        (_, Some(Reply::Error(e))) => (error_status(e), error(e)),
+        (Shape::Update, _) => ("204 No Content", Body::Empty),
+        _ => no_reply,
+    }
+}
+
+/// The status answering an error reply: the user wasn't allowed to, the cells took too
+/// long to settle, or else something in the request itself was wrong
+fn error_status(error: &str) -> &'static str {
+    if is_refusal(error) || error.starts_with(FOLLOWING) {
+        "403 Forbidden"
+    } else if error.starts_with(TIMED_OUT) {
+        "503 Service Unavailable"
+    } else {
+        "400 Bad Request"
+    }
+}
+
+/// Writes `stats` replies as Prometheus metrics, e.g. `rsheet_normal_queue 0`
+fn prometheus_text(replies: Vec<Reply>) -> String {
+    let mut text = String::new();
+    for reply in replies {
+        if let Reply::Value(name, CellValue::Int(n)) = reply {
+            let kind = if name.ends_with("_total") {
+                "counter"
+            } else {
+                "gauge"
+            };
+            text.push_str(&format!("# TYPE rsheet_{} {}\n", name, kind));
+            text.push_str(&format!("rsheet_{} {}\n", name, n));
+        }
+    }
+    text
+}
+
+/// Converts a cell value to JSON, keeping errors distinguishable from strings
+pub(crate) fn value_to_json(value: CellValue) -> Value {
+    match value {
+        CellValue::Int(i) => json!(i),
+        CellValue::String(s) => json!(s),
+        CellValue::Error(e) => json!({ "error": e }),
+        CellValue::None => Value::Null,
+    }
+}
+
+/// Reads one HTTP request and translates it into the sheet commands that answer it
+fn translate(reader: &mut impl BufRead) -> Result<(Vec<String>, Shape), Rejection> {
+    let request_line = read_line(reader)
+        .map_err(|_| Rejection::new("400 Bad Request", "unreadable request"))?
+        .ok_or_else(|| Rejection::new("414 URI Too Long", "request line too long"))?;
+    let mut parts = request_line.split_whitespace();
+    let (Some(method), Some(target)) = (parts.next(), parts.next()) else {
+        return Err(Rejection::new("400 Bad Request", "malformed request line"));
+    };
+
+    let mut content_length = 0;
+    let mut login = None;
+    let too_large = || Rejection::new("431 Request Header Fields Too Large", "headers too large");
+    for headers in 0.. {
+        match read_line(reader) {
+            Ok(Some(header)) if header.trim().is_empty() => break,
+            Ok(_) if headers == MAX_HEADERS => return Err(too_large()),
+            Ok(Some(header)) => {
+                if let Some((name, value)) = header.split_once(':') {
+                    if name.trim().eq_ignore_ascii_case("content-length") {
+                        content_length = value.trim().parse().map_err(|_| {
+                            Rejection::new("400 Bad Request", "invalid Content-Length")
+                        })?;
+                    } else if name.trim().eq_ignore_ascii_case("authorization") {
+                        login = Some(basic_login(value.trim()).ok_or_else(|| {
+                            Rejection::new("400 Bad Request", "invalid Authorization")
+                        })?);
+                    }
+                }
+            }
+            Ok(None) => return Err(too_large()),
+            Err(_) => return Err(Rejection::new("400 Bad Request", "unreadable headers")),
+        }
+    }
+
+    let path = target.split('?').next().unwrap_or(target);
+    let segments: Vec<&str> = path.trim_matches('/').split('/').collect();
+    let (mut commands, shape) = route(method, &segments, content_length, reader)?;
+    if let Some(login) = login {
+        commands.insert(0, login);
+    }
+    Ok((commands, shape))
+}
+
+/// Reads a line of at most `MAX_LINE_BYTES`, returning `None` if it is longer. The end
+/// of the input reads as an empty line.
+fn read_line(reader: &mut impl BufRead) -> std::io::Result<Option<String>> {
+    let mut line = String::new();
+    reader
+        .take(MAX_LINE_BYTES as u64 + 1)
+        .read_line(&mut line)?;
+    Ok((line.len() <= MAX_LINE_BYTES).then_some(line))
+}
+
+/// Turns `Basic <base64 of name:password>` into the `login` command it stands for
+fn basic_login(authorization: &str) -> Option<String> {
+    let (scheme, credentials) = authorization.split_once(' ')?;
+    if !scheme.eq_ignore_ascii_case("basic") {
+        return None;
+    }
+    let credentials = String::from_utf8(decode_base64(credentials.trim())?).ok()?;
+    let (name, password) = credentials.split_once(':')?;
+    // Both must survive being sent as words of a command
+    if [name, password]
+        .iter()
+        .any(|word| word.is_empty() || word.contains(char::is_whitespace))
+    {
+        return None;
+    }
+    Some(format!("login {} {}", name, password))
+}
+
+/// Decodes standard, padded base64
+fn decode_base64(s: &str) -> Option<Vec<u8>> {
+    let mut decoded = Vec::new();
+    let (mut bits, mut len) = (0u32, 0);
+    for c in s.trim_end_matches('=').bytes() {
+        let sextet = match c {
+            b'A'..=b'Z' => c - b'A',
+            b'a'..=b'z' => c - b'a' + 26,
+            b'0'..=b'9' => c - b'0' + 52,
+            b'+' => 62,
+            b'/' => 63,
+            _ => return None,
+        };
+        bits = (bits << 6) | u32::from(sextet);
+        len += 6;
+        if len >= 8 {
+            len -= 8;
+            decoded.push((bits >> len) as u8);
+            bits &= (1 << len) - 1;
+        }
+    }
+    Some(decoded)
+}
+
+/// Translates a request for a path into sheet commands, reading its body if it has one
+fn route(
+    method: &str,
+    segments: &[&str],
+    content_length: usize,
+    reader: &mut impl BufRead,
+) -> Result<(Vec<String>, Shape), Rejection> {
+    match (method, segments) {
+        ("GET", ["cells", cell]) => {
+            let cell = parse_cell(cell)?;
+            Ok((vec![format!("get {}", cell.key())], Shape::Cell))
+        }
+        ("PUT", ["cells", cell]) => {
+            let cell = parse_cell(cell)?;
+            if content_length > MAX_BODY_BYTES {
+                return Err(Rejection::new("413 Payload Too Large", "body too large"));
+            }
+            let mut body = vec![0; content_length];
+            reader
+                .read_exact(&mut body)
+                .map_err(|_| Rejection::new("400 Bad Request", "truncated body"))?;
+            let expr = String::from_utf8(body)
+                .map_err(|_| Rejection::new("400 Bad Request", "body is not UTF-8"))?;
+            let expr = expr.trim();
+            if expr.is_empty() || expr.contains('\n') {
+                return Err(Rejection::new(
+                    "400 Bad Request",
+                    "body must be a single-line expression",
+                ));
+            }
+            Ok((vec![format!("set {} {}", cell.key(), expr)], Shape::Update))
+        }
+        ("GET", ["range", range]) => {
+            let range: CellRange = range
+                .parse()
+                .map_err(|e: String| Rejection::new("400 Bad Request", e))?;
+            if range.cell_count() > MAX_RANGE_CELLS {
+                return Err(Rejection::new("400 Bad Request", "range too large"));
+            }
+            // Written out in full so even a one-cell range is a range query
+            let (start, end) = (range.key(&range.start), cell_id_to_string(&range.end));
+            Ok((vec![format!("get {}_{}", start, end)], Shape::Range))
+        }
+        ("GET", ["metrics"]) => Ok((vec!["stats".to_string()], Shape::Metrics)),
+        (_, ["cells", _]) | (_, ["range", _]) | (_, ["metrics"]) => Err(Rejection::new(
+            "405 Method Not Allowed",
+            "method not allowed",
+        )),
+        _ => Err(Rejection::new("404 Not Found", "not found")),
+    }
+}
+
+/// Parses a path segment naming exactly one cell
+fn parse_cell(cell: &str) -> Result<CellRef, Rejection> {
+    match cell.parse::<CellRef>() {
+        Ok(cell) => Ok(cell),
+        Err(_) => Err(Rejection::new(
+            "400 Bad Request",
+            format!("invalid cell: {}", cell),
+        )),
+    }
+}
+
+/// Writes a complete response and closes the connection
+fn respond(socket: std::io::Result<TcpStream>, status: &str, body: Option<Value>) {
+    let body = body.map(|b| b.to_string()).unwrap_or_default();
+    respond_with(socket, status, "application/json", &body);
+}
+
+/// Writes a complete response with a body of the given type and closes the connection
+fn respond_with(socket: std::io::Result<TcpStream>, status: &str, content_type: &str, body: &str) {
+    let Ok(mut socket) = socket else {
+        return;
+    };
+    let mut response = format!("HTTP/1.1 {}\r\nConnection: close\r\n", status);
+    if !body.is_empty() {
+        response.push_str(&format!("Content-Type: {}\r\n", content_type));
+    }
+    response.push_str(&format!("Content-Length: {}\r\n\r\n{}", body.len(), body));
+    let _ = socket.write_all(response.as_bytes());
+    let _ = socket.flush();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth::AccessControl;
+
+    fn translated(request: &str) -> Result<(Vec<String>, Shape), Rejection> {
+        translate(&mut request.as_bytes())
+    }
+
+    fn status(request: &str) -> &'static str {
+        translated(request).unwrap_err().status
+    }
+
+    #[test]
+    fn test_translates_requests_into_commands() {
+        assert_eq!(
+            translated("GET /cells/Sales!B2 HTTP/1.1\r\n\r\n").unwrap(),
+            (vec!["get Sales!B2".to_string()], Shape::Cell)
+        );
+        assert_eq!(
+            translated("PUT /cells/A1 HTTP/1.1\r\nContent-Length: 7\r\n\r\nB1 + 1\n").unwrap(),
+            (vec!["set A1 B1 + 1".to_string()], Shape::Update)
+        );
+        assert_eq!(
+            translated("GET /range/B2 HTTP/1.1\r\n\r\n").unwrap(),
+            (vec!["get B2_B2".to_string()], Shape::Range)
+        );
+        assert_eq!(
+            translated("GET /metrics?verbose HTTP/1.1\r\nAuthorization: Basic YW5uOnB3\r\n\r\n")
+                .unwrap(),
+            (
+                vec!["login ann pw".to_string(), "stats".to_string()],
+                Shape::Metrics
+            )
+        );
+
+        assert_eq!(
+            status("DELETE /cells/A1 HTTP/1.1\r\n\r\n"),
+            "405 Method Not Allowed"
+        );
+        assert_eq!(status("GET /sheets HTTP/1.1\r\n\r\n"), "404 Not Found");
+        assert_eq!(status("GET /cells/1A HTTP/1.1\r\n\r\n"), "400 Bad Request");
+        assert_eq!(
+            status("GET /range/A1_Z1000 HTTP/1.1\r\n\r\n"),
+            "400 Bad Request"
+        );
+        assert_eq!(status("GET\r\n\r\n"), "400 Bad Request");
+        assert_eq!(
+            status("PUT /cells/A1 HTTP/1.1\r\nContent-Length: 100000\r\n\r\n"),
+            "413 Payload Too Large"
+        );
+        assert_eq!(
+            status("PUT /cells/A1 HTTP/1.1\r\nContent-Length: 4\r\n\r\n1\n2\n"),
+            "400 Bad Request"
+        );
+        assert_eq!(
+            status("PUT /cells/A1 HTTP/1.1\r\nContent-Length: 9\r\n\r\n1"),
+            "400 Bad Request"
+        );
+    }
+
+    #[test]
+    fn test_caps_request_line_and_headers() {
+        let long = "x".repeat(MAX_LINE_BYTES);
+        assert_eq!(
+            status(&format!("GET /{} HTTP/1.1\r\n\r\n", long)),
+            "414 URI Too Long"
+        );
+        assert_eq!(
+            status(&format!(
+                "GET /metrics HTTP/1.1\r\nX-Long: {}\r\n\r\n",
+                long
+            )),
+            "431 Request Header Fields Too Large"
+        );
+
+        let headers = |n: usize| "X-Header: 1\r\n".repeat(n);
+        let request = |n: usize| format!("GET /metrics HTTP/1.1\r\n{}\r\n", headers(n));
+        assert!(translated(&request(MAX_HEADERS)).is_ok());
+        assert_eq!(
+            status(&request(MAX_HEADERS + 1)),
+            "431 Request Header Fields Too Large"
+        );
+    }
+
+    #[test]
+    fn test_decodes_basic_logins() {
+        assert_eq!(decode_base64("aGVsbG8=").as_deref(), Some(&b"hello"[..]));
+        assert_eq!(decode_base64("aGk").as_deref(), Some(&b"hi"[..]));
+        assert_eq!(decode_base64("aGk*"), None);
+
+        assert_eq!(
+            basic_login("Basic YW5uOnB3").as_deref(),
+            Some("login ann pw")
+        );
+        assert_eq!(
+            basic_login("basic  YW5uOnB3").as_deref(),
+            Some("login ann pw")
+        );
+        for rejected in [
+            "Bearer YW5uOnB3",
+            "Basic",
+            "Basic YW5uOnAgdw==",
+            "Basic YW5ucHc=",
+            "Basic !!!!",
+        ] {
+            assert_eq!(basic_login(rejected), None, "{}", rejected);
+        }
+    }
+
+    #[test]
+    fn test_writes_stats_as_prometheus_metrics() {
+        let replies = vec![
+            Reply::Value("evaluations_total".to_string(), CellValue::Int(3)),
+            Reply::Value("dirty_cells".to_string(), CellValue::Int(0)),
+            Reply::Value("name".to_string(), CellValue::String("x".to_string())),
+            Reply::Error("oops".to_string()),
+        ];
+        assert_eq!(
+            prometheus_text(replies),
+            "# TYPE rsheet_evaluations_total counter\n\
+             rsheet_evaluations_total 3\n\
+             # TYPE rsheet_dirty_cells gauge\n\
+             rsheet_dirty_cells 0\n"
+        );
+    }
+
+    /// The status line sent back for a request of `shape` answered with `replies`
+    fn status_line(shape: Shape, replies: Vec<Reply>) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (socket, _) = listener.accept().unwrap();
+        drop(HttpWriter {
+            socket,
+            id: String::new(),
+            shape,
+            replies,
+        });
+        let mut line = String::new();
+        BufReader::new(client).read_line(&mut line).unwrap();
+        line.trim_end().to_string()
+    }
+
+    #[test]
+    fn test_answers_errors_with_matching_statuses() {
+        let access: AccessControl = "
+            user carol letmein read-only
+            user bob hunter2 read-write
+            protect A1_A2 alice
+        "
+        .parse()
+        .unwrap();
+        let a1 = "A1".parse().unwrap();
+        let error = |e: String| vec![Reply::Error(e)];
+        for refusal in [
+            access.check_cell(Some("carol"), &a1).unwrap_err(),
+            access.check_cell(Some("bob"), &a1).unwrap_err(),
+            format!("{} 127.0.0.1:6991", FOLLOWING),
+        ] {
+            assert_eq!(
+                status_line(Shape::Update, error(refusal.clone())),
+                "HTTP/1.1 403 Forbidden",
+                "{}",
+                refusal
+            );
+            assert_eq!(
+                status_line(Shape::Range, error(refusal)),
+                "HTTP/1.1 403 Forbidden"
+            );
+        }
+        assert_eq!(
+            status_line(Shape::Update, Vec::new()),
+            "HTTP/1.1 204 No Content"
+        );
+        assert_eq!(
+            status_line(Shape::Cell, error(NOT_LOGGED_IN.to_string())),
+            "HTTP/1.1 401 Unauthorized"
+        );
+
+        let timed_out = format!("{} A1", TIMED_OUT);
+        assert_eq!(
+            status_line(Shape::Cell, error(timed_out.clone())),
+            "HTTP/1.1 503 Service Unavailable"
+        );
+        assert_eq!(
+            response(&Shape::Range, error(timed_out)).0,
+            "503 Service Unavailable"
+        );
+        let cycle = FormulaError::Cycle(vec!["A1".to_string(), "B1".to_string()]);
+        assert_eq!(response(&Shape::Cell, error(cycle.to_string())).0, "200 OK");
+        assert_eq!(
+            response(&Shape::Cell, error("Invalid cell 1A".to_string())).0,
+            "400 Bad Request"
+        );
+        assert_eq!(
+            response(&Shape::Range, Vec::new()).0,
+            "500 Internal Server Error"
+        );
+    }
+}
@@ -1,17 +1,121 @@
-use std::collections::{HashMap, VecDeque};
+mod auth;
+mod command;
+mod csv_file;
+mod display;
+mod explain;
+mod formula;
+mod graph;
+mod history;
+pub mod http;
+mod metrics;
+mod net;
+mod persist;
+mod reference;
+mod replicate;
+mod scheduler;
+mod session;
+mod store;
+mod structure;
+mod subscribe;
+
+use std::collections::{HashMap, HashSet};
+use std::convert::Infallible;
 use std::error::Error;
-use std::sync::{
-    mpsc::{channel, Sender},
-    Arc, Mutex,
-};
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
 use std::thread;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
-use rsheet_lib::cell_expr::{CellArgument, CellExpr};
+use rsheet_lib::cell_expr::CellArgument;
 use rsheet_lib::cell_value::CellValue;
-use rsheet_lib::command::{CellIdentifier, Command};
+use rsheet_lib::command::CellIdentifier;
 use rsheet_lib::connect::{Connection, Manager, Reader, Writer};
 use rsheet_lib::replies::Reply;
+use tokio::sync::mpsc::{unbounded_channel, UnboundedSender};
+
+use auth::AccessControl;
+use command::{CellRange, CellRef, Request, MAX_FORMAT_CELLS, MAX_RANGE_CELLS};
+use display::Format;
+use formula::{FormulaError, Function, Functions};
+use graph::DepGraph;
+use history::{Edit, History};
+use http::HttpManager;
+use metrics::Metrics;
+use persist::Journal;
+use replicate::{Leader, Stopped};
+use scheduler::Scheduler;
+use session::Session;
+use store::Store;
+use structure::StructuralEdit;
+use subscribe::Subscriptions;
+
+/// Name the default sheet can be referred to by; its cells are keyed without a prefix
+const DEFAULT_SHEET: &str = "Sheet1";
+
+/// Options controlling how the server runs
+#[derive(Debug, Clone)]
+pub struct ServerConfig {
+    /// Directory holding the write-ahead log and snapshots; `None` keeps the sheet in memory only
+    pub data_dir: Option<PathBuf>,
+    /// Number of logged commands between snapshots
+    pub snapshot_every: usize,
+    /// Address to also serve the HTTP/JSON API on
+    pub http_addr: Option<SocketAddr>,
+    /// Number of threads evaluating cells
+    pub workers: usize,
+    /// How long `get` waits for a cell to be recalculated; `None` waits indefinitely
+    pub get_timeout: Option<Duration>,
+    /// File of users who may log in (see the `auth` module); `None` lets anyone read and write
+    pub users_file: Option<PathBuf>,
+    /// How often cells calling volatile functions such as `NOW()`, and the cells reading
+    /// them, are recalculated; `None` only recalculates them when they are edited
+    pub volatile_interval: Option<Duration>,
+    /// Leader to follow as a read-only copy (see the `replicate` module), taking over once
+    /// it goes away
+    pub follow: Option<SocketAddr>,
+    /// User name and password to log in to the leader with, when it has users configured
+    pub follow_login: Option<(String, String)>,
+    /// The only directory `import` and `export` may use; `None` turns them off
+    pub files_dir: Option<PathBuf>,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        ServerConfig {
+            data_dir: None,
+            snapshot_every: 1000,
+            http_addr: None,
+            workers: 4,
+            get_timeout: None,
+            users_file: None,
+            volatile_interval: Some(Duration::from_secs(1)),
+            follow: None,
+            follow_login: None,
+            files_dir: None,
+        }
+    }
+}
+
+/// Shared sheet state handed to every connection
+#[derive(Clone)]
+struct SheetState {
+    /// Every cell's expression, value and dependencies
+    cells: Arc<Mutex<Store>>,
+    functions: Arc<Mutex<Functions>>,
+    journal: Arc<Mutex<Journal>>,
+    subscriptions: Arc<Mutex<Subscriptions>>,
+    history: Arc<Mutex<History>>,
+    scheduler: Arc<Scheduler>,
+    metrics: Arc<Metrics>,
+    get_timeout: Option<Duration>,
+    /// Who may do what; `None` when no users are configured
+    access: Option<Arc<AccessControl>>,
+    /// The server this one is a read-only copy of, until it goes away
+    leader: Arc<Mutex<Option<SocketAddr>>>,
+    /// Where `import` and `export` read and write files, if anywhere
+    files_dir: Option<Arc<Path>>,
+}
 
 /// Converts column number to Excel-style letter format (e.g., 0 -> A, 25 -> Z, 26 -> AA)
 fn col_number_to_letters(mut col: u32) -> String {
@@ -32,331 +136,828 @@ fn cell_id_to_string(id: &CellIdentifier) -> String {
     format!("{}{}", col_str, row_str)
 }
 
+/// Builds the key a cell is stored under (e.g., "A1" on the default sheet, "Sales!B2" elsewhere)
+fn cell_key(sheet: Option<&str>, id: &CellIdentifier) -> String {
+    match sheet {
+        Some(sheet) => format!("{}!{}", sheet, cell_id_to_string(id)),
+        None => cell_id_to_string(id),
+    }
+}
+
+/// Splits a key or reference like "Sales!B2" into its sheet and the cells on it,
+/// treating the default sheet's name the same as no prefix at all
+fn split_sheet(name: &str) -> (Option<&str>, &str) {
+    match name.split_once('!') {
+        Some((sheet, cells)) if sheet != DEFAULT_SHEET => (Some(sheet), cells),
+        Some((_, cells)) => (None, cells),
+        None => (None, name),
+    }
+}
+
+/// Whether a string can name a sheet: a letter or underscore, then letters, digits or underscores
+fn is_sheet_name(s: &str) -> bool {
+    let mut chars = s.chars();
+    chars
+        .next()
+        .is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
 /// Parses a string like "A1" into a `CellIdentifier`
 fn parse_cell_id(s: &str) -> Option<CellIdentifier> {
-    let (col_str, row_str) = s
-        .trim()
-        .chars()
-        .partition::<String, _>(|c| c.is_ascii_alphabetic());
+    let s = s.trim();
+    let (col_str, row_str) = s.split_at(s.find(|c: char| !c.is_ascii_alphabetic())?);
     let col = col_str
-        .chars()
-        .fold(0u32, |acc, c| {
-            acc * 26 + (c.to_ascii_uppercase() as u32 - 'A' as u32 + 1)
-        })
+        .bytes()
+        .try_fold(0u32, |acc, c| {
+            acc.checked_mul(26)?
+                .checked_add(u32::from(c.to_ascii_uppercase() - b'A') + 1)
+        })?
         .checked_sub(1)?;
     let row = row_str.parse::<u32>().ok()?.checked_sub(1)?;
     Some(CellIdentifier { col, row })
 }
 
-/// Extracts variable data (scalar, vector, or matrix) from the sheet based on the variable name
-fn extract_variable(sheet: &HashMap<String, CellValue>, name: &str) -> Option<CellArgument> {
-    if name.contains('_') {
-        let parts: Vec<_> = name.split('_').collect();
-        if parts.len() != 2 {
-            return None;
-        }
+/// Extracts variable data (scalar, vector, or matrix) from the sheet based on the variable name,
+/// which may be qualified with a sheet (e.g., "Sales!A1_B3")
+fn extract_variable(store: &Store, name: &str) -> Option<CellArgument> {
+    let (sheet_name, name) = split_sheet(name);
+    let Some((start, end)) = name.split_once('_') else {
+        // Single value
+        let cell = CellRef {
+            sheet: sheet_name.map(str::to_string),
+            id: parse_cell_id(name)?,
+        };
+        return Some(CellArgument::Value(store.value(&cell)));
+    };
+    let start_id = parse_cell_id(start)?;
+    let end_id = parse_cell_id(end)?;
+    let mut rows = store.range(sheet_name, start_id, end_id);
 
-        let start_id = parse_cell_id(parts[0])?;
-        let end_id = parse_cell_id(parts[1])?;
-
-        if start_id.row == end_id.row {
-            // Extract horizontal vector
-            let mut values = vec![];
-            for col in start_id.col..=end_id.col {
-                let key = cell_id_to_string(&CellIdentifier {
-                    col,
-                    row: start_id.row,
-                });
-                values.push(sheet.get(&key).cloned().unwrap_or(CellValue::None));
-            }
-            Some(CellArgument::Vector(values))
-        } else if start_id.col == end_id.col {
-            // Extract vertical vector
-            let mut values = vec![];
-            for row in start_id.row..=end_id.row {
-                let key = cell_id_to_string(&CellIdentifier {
-                    col: start_id.col,
-                    row,
-                });
-                values.push(sheet.get(&key).cloned().unwrap_or(CellValue::None));
-            }
-            Some(CellArgument::Vector(values))
-        } else {
-            // Extract 2D matrix
-            let mut matrix = vec![];
-            for row in start_id.row..=end_id.row {
-                let mut row_vec = vec![];
-                for col in start_id.col..=end_id.col {
-                    let key = cell_id_to_string(&CellIdentifier { col, row });
-                    row_vec.push(sheet.get(&key).cloned().unwrap_or(CellValue::None));
-                }
-                matrix.push(row_vec);
-            }
-            Some(CellArgument::Matrix(matrix))
-        }
+    if start_id.row == end_id.row {
+        // Horizontal vector
+        Some(CellArgument::Vector(rows.pop().unwrap_or_default()))
+    } else if start_id.col == end_id.col {
+        // Vertical vector
+        Some(CellArgument::Vector(rows.into_iter().flatten().collect()))
     } else {
-        // Single value
-        Some(CellArgument::Value(
-            sheet.get(name).cloned().unwrap_or(CellValue::None),
-        ))
+        Some(CellArgument::Matrix(rows))
     }
 }
 
 /// Starts the rsheet server with asynchronous dependency tracking
-pub fn start_server<M>(mut manager: M) -> Result<(), Box<dyn Error>>
+pub fn start_server<M>(manager: M) -> Result<(), Box<dyn Error>>
 where
     M: Manager + Send + 'static,
 {
-    let sheet = Arc::new(Mutex::new(HashMap::new()));
-    let exprs = Arc::new(Mutex::new(HashMap::new()));
-    let status = Arc::new(Mutex::new(HashMap::new()));
-    let deps = Arc::new(Mutex::new(HashMap::<String, Vec<String>>::new()));
-    let (tx, rx) = channel::<String>();
+    start_server_with_config(manager, ServerConfig::default())
+}
 
-    // Background worker thread: prioritizes non-blocking expressions
-    {
-        let sheet = Arc::clone(&sheet);
-        let exprs = Arc::clone(&exprs);
-        let status = Arc::clone(&status);
-        let deps = Arc::clone(&deps);
-        let tx = tx.clone();
-
-        thread::spawn(move || {
-            let mut normal_queue: VecDeque<String> = VecDeque::new();
-            let mut delayed_queue: VecDeque<String> = VecDeque::new();
-
-            loop {
-                if let Some(key) = normal_queue.pop_front() {
-                    evaluate_and_store(&key, &sheet, &exprs, &status, &deps, &tx);
-                    continue;
-                }
+/// Starts the rsheet server, restoring and persisting the sheet as configured
+pub fn start_server_with_config<M>(manager: M, config: ServerConfig) -> Result<(), Box<dyn Error>>
+where
+    M: Manager + Send + 'static,
+{
+    let state = launch(&config)?;
+    serve_connections(manager, state.clone());
+    shut_down(&state)
+}
+
+/// Starts the rsheet server with clients connecting over TCP to `addr`, each served as
+/// a task on an async runtime rather than a thread of its own; runs until interrupted
+pub fn start_async_server(addr: SocketAddr, config: ServerConfig) -> Result<(), Box<dyn Error>> {
+    let state = launch(&config)?;
+    net::serve(addr, state.clone())?;
+    shut_down(&state)
+}
 
-                if let Some(key) = delayed_queue.pop_front() {
-                    evaluate_and_store(&key, &sheet, &exprs, &status, &deps, &tx);
-                    continue;
+/// Restores the sheet and starts the evaluators and the HTTP API, ready for clients
+fn launch(config: &ServerConfig) -> Result<SheetState, Box<dyn Error>> {
+    let (journal, replay) = match &config.data_dir {
+        Some(dir) => Journal::open(dir, config.snapshot_every)?,
+        None => (Journal::in_memory(), Vec::new()),
+    };
+
+    let access = match &config.users_file {
+        Some(path) => Some(Arc::new(AccessControl::load(path)?)),
+        None => None,
+    };
+
+    let state = SheetState {
+        cells: Arc::new(Mutex::new(Store::default())),
+        functions: Arc::new(Mutex::new(Functions::new())),
+        journal: Arc::new(Mutex::new(journal)),
+        subscriptions: Arc::new(Mutex::new(Subscriptions::default())),
+        history: Arc::new(Mutex::new(History::default())),
+        scheduler: Arc::new(Scheduler::default()),
+        metrics: Arc::new(Metrics::default()),
+        get_timeout: config.get_timeout,
+        access,
+        leader: Arc::new(Mutex::new(None)),
+        files_dir: config.files_dir.as_deref().map(Arc::from),
+    };
+
+    // Evaluator pool: each thread takes whichever stale cell has all its inputs final
+    for _ in 0..config.workers.max(1) {
+        let state = state.clone();
+        thread::spawn(move || loop {
+            let task = state.scheduler.next_task(&state.cells);
+            let started = Instant::now();
+            let value = evaluate(&task.cell, &state);
+            state.metrics.record_evaluation(started.elapsed());
+            state.scheduler.finish(&task, &state.cells, |current| {
+                // A result computed from an outdated expression or inputs is thrown away
+                if current {
+                    let mut cells_guard = state.cells.lock().unwrap();
+                    cells_guard.update(&task.cell, |cell| cell.value = value.clone());
+                    drop(cells_guard);
+                    state
+                        .subscriptions
+                        .lock()
+                        .unwrap()
+                        .notify(&task.cell, &value);
                 }
+            });
+        });
+    }
 
-                match rx.recv() {
-                    Ok(key) => {
-                        let expr_str: String = {
-                            let exprs_guard = exprs.lock().unwrap();
-                            exprs_guard.get(key.as_str()).cloned().unwrap_or_default()
-                        };
-                        if expr_str.contains("sleep_then") {
-                            delayed_queue.push_back(key);
-                        } else {
-                            normal_queue.push_back(key);
-                        }
+    // Replay the persisted sheet; the evaluators re-evaluate every restored cell
+    let mut batch = None;
+    for line in replay {
+        match read_logged(&line, &mut batch) {
+            Ok(Some(Logged::Set(cell, expr))) => {
+                apply_set(&state, &cell, expr.as_deref());
+            }
+            Ok(Some(Logged::Batch(sets))) => {
+                apply_batch(&state, &sets);
+            }
+            Ok(Some(Logged::Restructure(edit))) => apply_restructure(&state, &edit),
+            Ok(Some(Logged::Define(function))) => apply_define(&state, function),
+            Ok(Some(Logged::Format(range, format))) => apply_format(&state, &range, &format),
+            Ok(None) => {}
+            Err(e) => eprintln!("Skipping {}", e),
+        }
+    }
+
+    // Volatile cells change with time alone, so they and everything reading them are
+    // recalculated on a timer
+    if let Some(interval) = config.volatile_interval {
+        let state = state.clone();
+        thread::spawn(move || loop {
+            thread::sleep(interval);
+            let mut volatile: Vec<CellRef> = state.cells.lock().unwrap().volatile().collect();
+            // A cell still recalculating from the last tick is left to finish first
+            volatile.retain(|cell| {
+                state
+                    .scheduler
+                    .wait_until_settled(cell, Some(Duration::ZERO))
+            });
+            if !volatile.is_empty() {
+                schedule_recalc(&state, &volatile);
+            }
+        });
+    }
+
+    // The HTTP API shares the sheet and lives as long as the server does
+    if let Some(addr) = config.http_addr {
+        let http = HttpManager::bind(addr)?;
+        let state = state.clone();
+        thread::spawn(move || serve_connections(http, state));
+    }
+
+    if let Some(addr) = config.follow {
+        let leader = Leader::connect(addr, config.follow_login.clone())?;
+        *state.leader.lock().unwrap() = Some(addr);
+        let state = state.clone();
+        thread::spawn(move || follow(&state, leader));
+    }
+
+    Ok(state)
+}
+
+/// Keeps this server a read-only copy of its leader until the leader goes away, then
+/// takes over so it accepts changes itself. Anything else that stops it following
+/// leaves it read-only, as the leader may well still be running.
+fn follow(state: &SheetState, mut leader: Leader) {
+    loop {
+        let Err(stopped) = copy_leader(state, &mut leader);
+        match stopped {
+            Stopped::Lost(e) => {
+                eprintln!("Lost {}: {}; reconnecting", leader.addr(), e);
+                match leader.reconnect() {
+                    // Catching up again replaces whatever was missed meanwhile
+                    Some(reconnected) => leader = reconnected,
+                    None => {
+                        *state.leader.lock().unwrap() = None;
+                        eprintln!("Could not reach {}; taking over", leader.addr());
+                        return;
                     }
-                    Err(_) => break,
                 }
             }
-        });
+            Stopped::Failed(e) => {
+                eprintln!(
+                    "Stopped following {}: {}; staying read-only",
+                    leader.addr(),
+                    e
+                );
+                return;
+            }
+        }
     }
+}
 
+/// Replaces the sheet with the leader's, then applies every change the leader logs,
+/// journaling each as if it were made here. Only returns once that fails.
+fn copy_leader(state: &SheetState, leader: &mut Leader) -> Result<Infallible, Stopped> {
+    let (mut stale, mut formatted): (Vec<CellRef>, Vec<CellRef>) = {
+        let cells_guard = state.cells.lock().unwrap();
+        let exprs = cells_guard.exprs().map(|(at, _)| at).collect();
+        let formatted = cells_guard
+            .iter()
+            .filter(|(_, cell)| cell.format != Format::default())
+            .map(|(at, _)| at)
+            .collect();
+        (exprs, formatted)
+    };
+    stale.sort();
+    for cell in stale {
+        set_cell(state, &cell, None)?;
+    }
+    formatted.sort();
+    for cell in formatted {
+        format_cells(state, &CellRange::from(&cell), &Format::default())?;
+    }
+
+    let mut batch = None;
+    loop {
+        let line = leader.next_command()?;
+        match read_logged(&line, &mut batch)? {
+            Some(Logged::Set(cell, expr)) => set_cell(state, &cell, expr.as_deref()).map(drop),
+            Some(Logged::Batch(sets)) => set_cells(state, &sets).map(drop),
+            Some(Logged::Restructure(edit)) => restructure(state, &edit),
+            Some(Logged::Define(function)) => define_function(state, function),
+            Some(Logged::Format(range, format)) => format_cells(state, &range, &format),
+            None => Ok(()),
+        }?;
+    }
+}
+
+/// One change read back from a journal or a leader's stream of it
+enum Logged {
+    /// A cell's new expression, or `None` where undo removed it
+    Set(CellRef, Option<String>),
+    /// `set`s committed together
+    Batch(Vec<(CellRef, String)>),
+    Restructure(StructuralEdit),
+    Define(Function),
+    Format(CellRange, Format),
+}
+
+/// Reads one line of a journal, holding back the `set`s between `begin` and `commit`
+/// in `batch` and only returning them once the whole batch is read
+fn read_logged(
+    line: &str,
+    batch: &mut Option<Vec<(CellRef, String)>>,
+) -> Result<Option<Logged>, String> {
+    let error = || format!("unreadable journal entry: {}", line);
+    // `clear` only ever appears in the journal, where undo removed a cell's expression
+    if let Some(cell) = line.strip_prefix("clear ") {
+        let cell = cell.parse::<CellRef>().map_err(|_| error())?;
+        return Ok(Some(Logged::Set(cell, None)));
+    }
+    let logged = match line.parse::<Request>().map_err(|_| error())? {
+        Request::Set { cell, expr } => match batch {
+            Some(sets) => {
+                sets.push((cell, expr));
+                return Ok(None);
+            }
+            None => Logged::Set(cell, Some(expr)),
+        },
+        Request::Begin => {
+            *batch = Some(Vec::new());
+            return Ok(None);
+        }
+        Request::Commit => Logged::Batch(batch.take().unwrap_or_default()),
+        Request::Restructure { edit } => Logged::Restructure(edit),
+        Request::Define { function } => Logged::Define(function),
+        Request::Format { range, format } => Logged::Format(range, format),
+        _ => return Err(error()),
+    };
+    Ok(Some(logged))
+}
+
+/// Finishes recalculating once clients are gone, then saves the sheet
+fn shut_down(state: &SheetState) -> Result<(), Box<dyn Error>> {
+    state.scheduler.wait_until_idle();
+
+    // Compact the log on a clean shutdown so the next start replays less
+    let mut journal = state.journal.lock().unwrap();
+    let functions_guard = state.functions.lock().unwrap();
+    let cells_guard = state.cells.lock().unwrap();
+    journal.snapshot(&functions_guard, cells_guard.iter())?;
+
+    Ok(())
+}
+
+/// Accepts connections from a manager until it runs out, handling each on its own thread
+fn serve_connections<M: Manager>(mut manager: M, state: SheetState) {
     let mut handles = Vec::new();
 
-    // Accept and process client connections
     while let Connection::NewConnection { reader, writer } = manager.accept_new_connection() {
-        let sheet_clone = Arc::clone(&sheet);
-        let exprs_clone = Arc::clone(&exprs);
-        let status_clone = Arc::clone(&status);
-        let deps_clone = Arc::clone(&deps);
-        let tx_clone = tx.clone();
+        let state_clone = state.clone();
+        let metrics = Arc::clone(&state.metrics);
 
         let handle = thread::spawn(move || {
-            if let Err(e) = handle_connection(
-                reader,
-                writer,
-                sheet_clone,
-                exprs_clone,
-                status_clone,
-                deps_clone,
-                tx_clone,
-            ) {
+            let _open = metrics.open_connection();
+            if let Err(e) = handle_connection(reader, writer, state_clone) {
                 eprintln!("Connection error: {}", e);
             }
         });
 
         handles.push(handle);
+        // Forget connections that have closed so a long-running server doesn't pile them up
+        handles.retain(|handle| !handle.is_finished());
     }
 
     for handle in handles {
         let _ = handle.join();
     }
-
-    Ok(())
 }
 
 /// Handles incoming client messages (set/get commands)
-fn handle_connection<R, W>(
-    mut recv: R,
-    mut send: W,
-    sheet: Arc<Mutex<HashMap<String, CellValue>>>,
-    exprs: Arc<Mutex<HashMap<String, String>>>,
-    status: Arc<Mutex<HashMap<String, bool>>>,
-    deps: Arc<Mutex<HashMap<String, Vec<String>>>>,
-    tx: Sender<String>,
-) -> Result<(), Box<dyn Error>>
+fn handle_connection<R, W>(mut recv: R, send: W, state: SheetState) -> Result<(), Box<dyn Error>>
 where
     R: Reader + Send + 'static,
     W: Writer + Send + 'static,
 {
+    // Shared with the pusher thread once the connection subscribes to something
+    let send = Arc::new(Mutex::new(send));
+    let pushed_to = Arc::clone(&send);
+    let mut session = Session::new(state, recv.id(), move || {
+        spawn_pusher(Arc::clone(&pushed_to))
+    });
+
     loop {
         match recv.read_message() {
             rsheet_lib::connect::ReadMessageResult::Message(msg) => {
-                let reply = match msg.parse::<Command>() {
-                    Ok(Command::Get { cell_identifier }) => {
-                        let key = cell_id_to_string(&cell_identifier);
-                        // Wait until value is ready (status == true)
-                        loop {
-                            let ready = {
-                                let status_guard = status.lock().unwrap();
-                                *status_guard.get(&key).unwrap_or(&true)
-                            };
-                            if ready {
-                                break;
-                            }
-                            thread::sleep(Duration::from_millis(5));
-                        }
-                        let value = {
-                            let sheet_guard = sheet.lock().unwrap();
-                            sheet_guard.get(&key).cloned().unwrap_or(CellValue::None)
-                        };
-                        match value {
-                            CellValue::Error(msg) if msg == "Dependency error" => {
-                                Reply::Error("Dependency error".to_string())
-                            }
-                            _ => Reply::Value(key, value),
-                        }
-                    }
-                    Ok(Command::Set {
-                        cell_identifier,
-                        cell_expr,
-                    }) => {
-                        let key = cell_id_to_string(&cell_identifier);
-                        {
-                            let mut exprs_guard = exprs.lock().unwrap();
-                            exprs_guard.insert(key.clone(), cell_expr.clone());
-                        }
-                        {
-                            let mut status_guard = status.lock().unwrap();
-                            status_guard.insert(key.clone(), false);
-                        }
-                        {
-                            // Extract and expand all dependencies
-                            let mut deps_guard = deps.lock().unwrap();
-                            let mut expanded_vars = Vec::new();
-                            for var in CellExpr::new(&cell_expr).find_variable_names() {
-                                if var.contains('_') {
-                                    if let Some((start, end)) = var.split_once('_') {
-                                        if let (Some(start_id), Some(end_id)) =
-                                            (parse_cell_id(start), parse_cell_id(end))
-                                        {
-                                            if start_id.row == end_id.row {
-                                                for col in start_id.col..=end_id.col {
-                                                    expanded_vars.push(cell_id_to_string(
-                                                        &CellIdentifier {
-                                                            col,
-                                                            row: start_id.row,
-                                                        },
-                                                    ));
-                                                }
-                                            } else if start_id.col == end_id.col {
-                                                for row in start_id.row..=end_id.row {
-                                                    expanded_vars.push(cell_id_to_string(
-                                                        &CellIdentifier {
-                                                            col: start_id.col,
-                                                            row,
-                                                        },
-                                                    ));
-                                                }
-                                            } else {
-                                                for row in start_id.row..=end_id.row {
-                                                    for col in start_id.col..=end_id.col {
-                                                        expanded_vars.push(cell_id_to_string(
-                                                            &CellIdentifier { col, row },
-                                                        ));
-                                                    }
-                                                }
-                                            }
-                                        }
-                                    }
-                                } else {
-                                    expanded_vars.push(var);
-                                }
-                            }
-                            deps_guard.insert(key.clone(), expanded_vars);
-                        }
-                        let _ = tx.send(key);
-                        continue;
+                for reply in session.handle(&msg) {
+                    let written = send.lock().unwrap().write_message(reply);
+                    match written {
+                        rsheet_lib::connect::WriteMessageResult::Ok => {}
+                        rsheet_lib::connect::WriteMessageResult::ConnectionClosed => return Ok(()),
+                        rsheet_lib::connect::WriteMessageResult::Err(e) => return Err(Box::new(e)),
                     }
-                    Err(e) => Reply::Error(e),
-                };
-
-                match send.write_message(reply) {
-                    rsheet_lib::connect::WriteMessageResult::Ok => {}
-                    rsheet_lib::connect::WriteMessageResult::ConnectionClosed => break,
-                    rsheet_lib::connect::WriteMessageResult::Err(e) => return Err(Box::new(e)),
                 }
             }
-            rsheet_lib::connect::ReadMessageResult::ConnectionClosed => break,
+            rsheet_lib::connect::ReadMessageResult::ConnectionClosed => return Ok(()),
             rsheet_lib::connect::ReadMessageResult::Err(e) => return Err(Box::new(e)),
         }
     }
+}
+
+/// Spawns a thread that writes pushed replies to a connection, returning its queue
+fn spawn_pusher<W>(send: Arc<Mutex<W>>) -> UnboundedSender<Reply>
+where
+    W: Writer + Send + 'static,
+{
+    let (tx, mut rx) = unbounded_channel::<Reply>();
+    thread::spawn(move || {
+        while let Some(reply) = rx.blocking_recv() {
+            let written = send.lock().unwrap().write_message(reply);
+            if !matches!(written, rsheet_lib::connect::WriteMessageResult::Ok) {
+                break;
+            }
+        }
+    });
+    tx
+}
+
+/// Waits until a cell has been recalculated, then returns its value
+fn wait_for_value(state: &SheetState, cell: &CellRef) -> Result<CellValue, String> {
+    if !state.scheduler.wait_until_settled(cell, state.get_timeout) {
+        return Err(format!("{} {}", scheduler::TIMED_OUT, cell));
+    }
+    Ok(state.cells.lock().unwrap().value(cell))
+}
+
+/// Waits until every cell in a range has been recalculated, giving up once the range
+/// as a whole has taken longer than a `get` may
+fn wait_for_range(state: &SheetState, range: &CellRange) -> Result<(), String> {
+    let deadline = state.get_timeout.map(|timeout| Instant::now() + timeout);
+    for cell in range.cells() {
+        let timeout = deadline.map(|deadline| deadline.saturating_duration_since(Instant::now()));
+        if !state.scheduler.wait_until_settled(&cell, timeout) {
+            return Err(format!("{} {}", scheduler::TIMED_OUT, cell));
+        }
+    }
     Ok(())
 }
 
-/// Evaluates a cell and stores the result, then re-triggers dependent evaluations
-fn evaluate_and_store(
-    key: &str,
-    sheet: &Arc<Mutex<HashMap<String, CellValue>>>,
-    exprs: &Arc<Mutex<HashMap<String, String>>>,
-    status: &Arc<Mutex<HashMap<String, bool>>>,
-    deps: &Arc<Mutex<HashMap<String, Vec<String>>>>,
-    tx: &Sender<String>,
-) {
-    let expr_str = {
-        let exprs_guard = exprs.lock().unwrap();
-        exprs_guard.get(key).cloned().unwrap_or_default()
-    };
-    let expr = CellExpr::new(&expr_str);
-    let mut context = HashMap::new();
+/// Waits until every cell in a range has been recalculated, then returns their values
+/// as a row-major JSON array, and how to show each of them as another if `formatted`
+fn get_range(
+    state: &SheetState,
+    range: &CellRange,
+    formatted: bool,
+) -> Result<(String, Option<String>), String> {
+    if range.cell_count() > MAX_RANGE_CELLS {
+        return Err(format!("{} has more than {} cells", range, MAX_RANGE_CELLS));
+    }
+    wait_for_range(state, range)?;
+
+    let cells_guard = state.cells.lock().unwrap();
+    let rows = cells_guard.range(range.sheet.as_deref(), range.start, range.end);
+    let displays = formatted.then(|| {
+        let displays: Vec<serde_json::Value> = (range.start.row..=range.end.row)
+            .zip(&rows)
+            .map(|(row, values)| {
+                (range.start.col..=range.end.col)
+                    .zip(values)
+                    .map(|(col, value)| {
+                        cells_guard.display(&range.cell(CellIdentifier { col, row }), value)
+                    })
+                    .collect()
+            })
+            .collect();
+        serde_json::Value::Array(displays).to_string()
+    });
+    drop(cells_guard);
+
+    let rows: Vec<serde_json::Value> = rows
+        .into_iter()
+        .map(|row| row.into_iter().map(http::value_to_json).collect())
+        .collect();
+    Ok((serde_json::Value::Array(rows).to_string(), displays))
+}
+
+/// Makes a client's edit to a cell, recording it so it can be undone
+fn edit_cell(
+    state: &SheetState,
+    conn: &str,
+    cell: &CellRef,
+    cell_expr: &str,
+) -> Result<(), String> {
+    // Hold the history across the edit so versions are recorded in the order they apply
+    let mut history = state.history.lock().unwrap();
+    let before = set_cell(state, cell, Some(cell_expr))?;
+    history.record(Edit {
+        conn: conn.to_string(),
+        cell: cell.clone(),
+        before,
+        after: Some(cell_expr.to_string()),
+    });
+    Ok(())
+}
+
+/// Applies a transaction's `set`s as one: they are journaled together, replace the
+/// cells' expressions together, and are recalculated in a single wave
+fn commit(state: &SheetState, conn: &str, sets: Vec<(CellRef, String)>) -> Result<(), String> {
+    if sets.is_empty() {
+        return Ok(());
+    }
+    let mut history = state.history.lock().unwrap();
+    let befores = set_cells(state, &sets)?;
+    for ((cell, expr), before) in sets.into_iter().zip(befores) {
+        history.record(Edit {
+            conn: conn.to_string(),
+            cell,
+            before,
+            after: Some(expr),
+        });
+    }
+    Ok(())
+}
+
+/// Copies a cell's expression into every cell of a range, shifting its relative references
+/// by each target's offset from the source
+fn copy_cell(
+    state: &SheetState,
+    conn: &str,
+    source: &CellRef,
+    targets: &CellRange,
+) -> Result<(), String> {
+    let expr = {
+        let cells_guard = state.cells.lock().unwrap();
+        cells_guard.expr(source).map(str::to_string)
+    }
+    .ok_or_else(|| format!("{} has no expression to copy", source))?;
+
+    // Work out every copy first so a reference falling off the sheet changes nothing
+    let mut copies = Vec::new();
+    for row in targets.start.row..=targets.end.row {
+        for col in targets.start.col..=targets.end.col {
+            let target = targets.cell(CellIdentifier { col, row });
+            let cols = i64::from(col) - i64::from(source.id.col);
+            let rows = i64::from(row) - i64::from(source.id.row);
+            let shifted = reference::shift(&expr, cols, rows).ok_or_else(|| {
+                format!(
+                    "Copying {} to {} moves a reference off the sheet",
+                    source, target
+                )
+            })?;
+            copies.push((target, shifted));
+        }
+    }
+    copies
+        .into_iter()
+        .try_for_each(|(target, expr)| edit_cell(state, conn, &target, &expr))
+}
+
+/// Journals a cell's new expression (or its removal), then applies it,
+/// returning the expression it replaced
+fn set_cell(
+    state: &SheetState,
+    cell: &CellRef,
+    cell_expr: Option<&str>,
+) -> Result<Option<String>, String> {
+    // Hold the journal across the update so snapshots and followers never miss it
+    let mut journal = state.journal.lock().unwrap();
+    match cell_expr {
+        Some(expr) => journal.record_set(cell, expr),
+        None => journal.record_clear(cell),
+    }
+    .map_err(|e| format!("Could not persist {}: {}", cell, e))?;
+    let before = apply_set(state, cell, cell_expr);
+    snapshot_if_due(state, &mut journal);
+    Ok(before)
+}
+
+/// Journals several cells' new expressions as one batch, then applies them together,
+/// returning the expressions they replaced
+fn set_cells(
+    state: &SheetState,
+    sets: &[(CellRef, String)],
+) -> Result<Vec<Option<String>>, String> {
+    let mut journal = state.journal.lock().unwrap();
+    journal
+        .record_batch(sets)
+        .map_err(|e| format!("Could not persist transaction: {}", e))?;
+    let befores = apply_batch(state, sets);
+    snapshot_if_due(state, &mut journal);
+    Ok(befores)
+}
+
+/// Compacts the log once enough has been written to it since the last snapshot
+fn snapshot_if_due(state: &SheetState, journal: &mut Journal) {
+    if journal.needs_snapshot() {
+        let functions_guard = state.functions.lock().unwrap();
+        let cells_guard = state.cells.lock().unwrap();
+        if let Err(e) = journal.snapshot(&functions_guard, cells_guard.iter()) {
+            eprintln!("Snapshot failed: {}", e);
+        }
+    }
+}
+
+/// Journals and applies how a range's cells should be shown
+fn format_cells(state: &SheetState, range: &CellRange, format: &Format) -> Result<(), String> {
+    if range.cell_count() > MAX_FORMAT_CELLS {
+        return Err(format!(
+            "{} has more than {} cells",
+            range, MAX_FORMAT_CELLS
+        ));
+    }
+    let mut journal = state.journal.lock().unwrap();
+    journal
+        .record_format(range, format)
+        .map_err(|e| format!("Could not persist format of {}: {}", range, e))?;
+    apply_format(state, range, format);
+    snapshot_if_due(state, &mut journal);
+    Ok(())
+}
+
+/// Sets the format of every cell in a range; values are unchanged, so nothing is
+/// recalculated
+fn apply_format(state: &SheetState, range: &CellRange, format: &Format) {
+    let mut cells_guard = state.cells.lock().unwrap();
+    for at in range.cells() {
+        cells_guard.update(&at, |cell| cell.format = format.clone());
+    }
+}
+
+/// Journals and applies an inserted or deleted row or column
+fn restructure(state: &SheetState, edit: &StructuralEdit) -> Result<(), String> {
+    // Hold the history so no edit lands halfway through the cells moving
+    let mut history = state.history.lock().unwrap();
+    let mut journal = state.journal.lock().unwrap();
+    journal
+        .record_restructure(edit)
+        .map_err(|e| format!("Could not persist {}: {}", edit, e))?;
+    apply_restructure(state, edit);
+    snapshot_if_due(state, &mut journal);
+    drop(journal);
+    history.restructure(|cell| edit.move_cell(cell));
+    Ok(())
+}
+
+/// Moves every cell a structural edit affects, rewrites every formula to follow,
+/// and recalculates whatever moved or changed
+fn apply_restructure(state: &SheetState, edit: &StructuralEdit) {
+    // Both the old and new positions of anything that moved need recalculating
+    let mut changed = HashSet::new();
     {
-        let sheet_guard = sheet.lock().unwrap();
-        for var in expr.find_variable_names() {
-            if let Some(arg) = extract_variable(&sheet_guard, &var) {
-                context.insert(var, arg);
+        let mut cells_guard = state.cells.lock().unwrap();
+        cells_guard.deps = DepGraph::default();
+        for (at, mut cell) in cells_guard.take_all() {
+            let Some(moved_to) = edit.move_cell(&at) else {
+                if cell.expr.is_some() {
+                    changed.insert(at);
+                }
+                continue;
+            };
+            if let Some(expr) = cell.expr.take() {
+                let rewritten = edit.rewrite(&expr, at.sheet.as_deref());
+                if moved_to != at || rewritten != expr {
+                    changed.insert(moved_to.clone());
+                    changed.insert(at);
+                }
+                let precedents = expand_dependencies(&moved_to, &rewritten);
+                cells_guard.deps.set_precedents(&moved_to, precedents);
+                cell.expr = Some(rewritten);
             }
+            cells_guard.update(&moved_to, |moved| *moved = cell);
         }
     }
-    let value = match expr.evaluate(&context) {
-        Ok(v) => v,
-        Err(_) => CellValue::Error("Dependency error".to_string()),
+    let changed: Vec<CellRef> = changed.into_iter().collect();
+    schedule_recalc(state, &changed);
+}
+
+/// Journals and applies a function definition
+fn define_function(state: &SheetState, function: Function) -> Result<(), String> {
+    let mut journal = state.journal.lock().unwrap();
+    journal
+        .record_define(&function)
+        .map_err(|e| format!("Could not persist {}: {}", function.key(), e))?;
+    apply_define(state, function);
+    snapshot_if_due(state, &mut journal);
+    Ok(())
+}
+
+/// Stores a function definition and recalculates every cell that calls it,
+/// directly or through other defined functions
+fn apply_define(state: &SheetState, function: Function) {
+    let affected = {
+        let mut functions_guard = state.functions.lock().unwrap();
+        let mut affected = HashSet::from([function.key()]);
+        functions_guard.insert(function.key(), Arc::new(function));
+        loop {
+            let callers: Vec<String> = functions_guard
+                .iter()
+                .filter(|(name, f)| {
+                    !affected.contains(*name) && f.calls().iter().any(|c| affected.contains(c))
+                })
+                .map(|(name, _)| name.clone())
+                .collect();
+            if callers.is_empty() {
+                break affected;
+            }
+            affected.extend(callers);
+        }
     };
-    {
-        let mut sheet_guard = sheet.lock().unwrap();
-        sheet_guard.insert(key.to_string(), value);
-    }
-    {
-        let mut status_guard = status.lock().unwrap();
-        status_guard.insert(key.to_string(), true);
+    let callers: Vec<CellRef> = {
+        let cells_guard = state.cells.lock().unwrap();
+        cells_guard
+            .exprs()
+            .filter(|(_, expr)| {
+                formula::called_functions(expr)
+                    .iter()
+                    .any(|name| affected.contains(name))
+            })
+            .map(|(at, _)| at)
+            .collect()
+    };
+    schedule_recalc(state, &callers);
+}
+
+/// Stores a cell's new expression (or removes it), records its dependencies and queues
+/// it for evaluation, returning the expression it replaced
+fn apply_set(state: &SheetState, at: &CellRef, cell_expr: Option<&str>) -> Option<String> {
+    let before = {
+        let mut cells_guard = state.cells.lock().unwrap();
+        let precedents = cell_expr
+            .map(|expr| expand_dependencies(at, expr))
+            .unwrap_or_default();
+        cells_guard.deps.set_precedents(at, precedents);
+        cells_guard.update(at, |cell| {
+            std::mem::replace(&mut cell.expr, cell_expr.map(str::to_string))
+        })
+    };
+    schedule_recalc(state, std::slice::from_ref(at));
+    before
+}
+
+/// Stores several cells' new expressions at once and recalculates them in a single wave,
+/// returning the expressions they replaced
+fn apply_batch(state: &SheetState, sets: &[(CellRef, String)]) -> Vec<Option<String>> {
+    let befores = {
+        let mut cells_guard = state.cells.lock().unwrap();
+        sets.iter()
+            .map(|(at, expr)| {
+                cells_guard
+                    .deps
+                    .set_precedents(at, expand_dependencies(at, expr));
+                cells_guard.update(at, |cell| cell.expr.replace(expr.clone()))
+            })
+            .collect()
+    };
+    let cells: Vec<CellRef> = sets.iter().map(|(at, _)| at.clone()).collect();
+    schedule_recalc(state, &cells);
+    befores
+}
+
+/// Marks cells, whose expressions or the functions they call have changed, and
+/// everything downstream of them for recalculation
+fn schedule_recalc(state: &SheetState, cells: &[CellRef]) {
+    let functions_guard = state.functions.lock().unwrap();
+    let mut cells_guard = state.cells.lock().unwrap();
+    for at in cells {
+        let behaviour = cells_guard
+            .expr(at)
+            .map(|expr| formula::behaviour(expr, &functions_guard))
+            .unwrap_or_default();
+        cells_guard.update(at, |cell| cell.behaviour = behaviour);
     }
+    drop(functions_guard);
 
-    // Reverse dependency map: notify all dependent cells
-    let reverse_map = {
-        let deps_guard = deps.lock().unwrap();
-        let mut map = HashMap::<String, Vec<String>>::new();
-        for (cell, vars) in deps_guard.iter() {
-            for var in vars {
-                map.entry(var.clone()).or_default().push(cell.clone());
+    let order = cells_guard.deps.recalc_order(cells);
+    // Formulas that sleep would hold up everything queued behind them, so they go last
+    let slow: HashSet<&CellRef> = order
+        .iter()
+        .flat_map(|component| &component.cells)
+        .filter(|at| cells_guard.get(at).is_some_and(|cell| cell.behaviour.slow))
+        .collect();
+    drop(cells_guard);
+    // Everything downstream is stale until an evaluator reaches it, except cycles,
+    // which are resolved right away so `get` reports them instead of waiting
+    state.scheduler.schedule(
+        &order,
+        |cell| slow.contains(cell),
+        |cycle| {
+            let error = mark_circular(cycle, &state.cells);
+            let mut subscriptions = state.subscriptions.lock().unwrap();
+            for cell in cycle {
+                subscriptions.notify(cell, &error);
             }
+        },
+    );
+}
+
+/// Extracts every cell the expression of the cell at `at` reads, expanding ranges into
+/// their individual cells and resolving unqualified references against the cell's own sheet
+fn expand_dependencies(at: &CellRef, cell_expr: &str) -> Vec<CellRef> {
+    let mut expanded = Vec::new();
+    for reference in reference::find_references(cell_expr) {
+        let qualified = reference.qualified(at.sheet.as_deref());
+        if let Ok(range) = qualified.parse::<CellRange>() {
+            expanded.extend(range.cells());
         }
-        map
+    }
+    expanded
+}
+
+/// Stores a circular dependency error in every cell of a cycle, returning the error
+fn mark_circular(members: &[CellRef], cells: &Mutex<Store>) -> CellValue {
+    let keys = members.iter().map(CellRef::key).collect();
+    let error = CellValue::Error(FormulaError::Cycle(keys).to_string());
+    {
+        let mut cells_guard = cells.lock().unwrap();
+        for member in members {
+            cells_guard.update(member, |cell| cell.value = error.clone());
+        }
+    }
+    error
+}
+
+/// Evaluates a cell's expression against the current values of the cells it reads
+fn evaluate(at: &CellRef, state: &SheetState) -> CellValue {
+    let (expr_str, context) = {
+        let cells_guard = state.cells.lock().unwrap();
+        // A cleared cell is simply empty
+        let Some(expr_str) = cells_guard.expr(at) else {
+            return CellValue::None;
+        };
+        let context: HashMap<_, _> = read_variables(&cells_guard, at, expr_str)
+            .into_iter()
+            .collect();
+        (expr_str.to_string(), context)
     };
-    if let Some(dependents) = reverse_map.get(key) {
-        for dep in dependents {
-            let _ = tx.send(dep.clone());
+    // Work from a copy so a slow formula doesn't hold up new definitions
+    let functions = state.functions.lock().unwrap().clone();
+    formula::evaluate(&expr_str, at.sheet.as_deref(), &context, &functions)
+}
+
+/// The variables the expression of the cell at `at` reads, in the order it first
+/// mentions them, each with the value its formula is given
+fn read_variables(store: &Store, at: &CellRef, expr: &str) -> Vec<(String, CellArgument)> {
+    let current_sheet = at.sheet.as_deref();
+    let mut variables: Vec<(String, CellArgument)> = Vec::new();
+    for reference in reference::find_references(expr) {
+        let var = reference.qualified(current_sheet);
+        if variables.iter().any(|(name, _)| *name == var) {
+            continue;
+        }
+        if let Some(arg) = extract_variable(store, &var) {
+            variables.push((var, arg));
         }
     }
+    variables
 }
@@ -1,30 +1,96 @@
 use std::error::Error;
+use std::fs;
+use std::path::PathBuf;
+use std::time::Duration;
 
 use clap::Parser;
-use rsheet::start_server;
-use rsheet_lib::connect::{resolve_address, ConnectionManager, TerminalManager};
+use rsheet::{start_async_server, start_server_with_config, ServerConfig};
+use rsheet_lib::connect::{resolve_address, TerminalManager};
 
 #[derive(Parser, Debug)]
 struct Args {
-    /// Address to listen on
+    /// Address to listen on; without one, commands are read from the terminal
     addr: Option<String>,
 
     /// Hides the contents of error messages
     #[arg(short, long, default_value_t = false)]
     mark_mode: bool,
+
+    /// Directory to persist the sheet in, restoring it from there on startup
+    #[arg(long)]
+    data_dir: Option<PathBuf>,
+
+    /// Number of logged commands between snapshots of the sheet
+    #[arg(long, default_value_t = 1000)]
+    snapshot_every: usize,
+
+    /// Address to also serve the HTTP/JSON API on
+    #[arg(long)]
+    http: Option<String>,
+
+    /// Number of threads evaluating cells
+    #[arg(long, default_value_t = 4)]
+    workers: usize,
+
+    /// Milliseconds `get` waits for a cell to be recalculated before giving up
+    #[arg(long)]
+    get_timeout_ms: Option<u64>,
+
+    /// File of users who must log in, with their roles and protected ranges
+    #[arg(long)]
+    users: Option<PathBuf>,
+
+    /// Milliseconds between recalculations of cells calling `NOW()` or `RAND()`; 0 never
+    /// recalculates them on a timer
+    #[arg(long, default_value_t = 1000)]
+    volatile_interval_ms: u64,
+
+    /// Address of a server to follow as a read-only copy, until it goes away
+    #[arg(long)]
+    follow: Option<String>,
+
+    /// User to log in to the followed server as, when it has users configured
+    #[arg(long, requires = "follow_password_file")]
+    follow_user: Option<String>,
+
+    /// File holding the password of `--follow-user`
+    #[arg(long, requires = "follow_user")]
+    follow_password_file: Option<PathBuf>,
+
+    /// Directory `import` and `export` read and write files in; without one, they are
+    /// turned off
+    #[arg(long)]
+    files_dir: Option<PathBuf>,
 }
 
 fn main() -> Result<(), Box<dyn Error>> {
     env_logger::init();
 
     let args = Args::parse();
+    let http_addr = args.http.as_deref().map(resolve_address).transpose()?;
+    let follow = args.follow.as_deref().map(resolve_address).transpose()?;
+    let follow_login = match (args.follow_user, args.follow_password_file) {
+        (Some(user), Some(path)) => Some((user, fs::read_to_string(path)?.trim().to_string())),
+        _ => None,
+    };
+    let config = ServerConfig {
+        data_dir: args.data_dir,
+        snapshot_every: args.snapshot_every,
+        http_addr,
+        workers: args.workers,
+        get_timeout: args.get_timeout_ms.map(Duration::from_millis),
+        users_file: args.users,
+        volatile_interval: (args.volatile_interval_ms > 0)
+            .then(|| Duration::from_millis(args.volatile_interval_ms)),
+        follow,
+        follow_login,
+        files_dir: args.files_dir,
+    };
 
     if let Some(addr) = args.addr {
-        let addr = resolve_address(&addr)?;
-        let manager = ConnectionManager::launch(addr.ip(), addr.port());
-        start_server(manager)
+        start_async_server(resolve_address(&addr)?, config)
     } else {
         let manager = TerminalManager::launch(args.mark_mode);
-        start_server(manager)
+        start_server_with_config(manager, config)
     }
 }
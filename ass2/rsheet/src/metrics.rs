@@ -0,0 +1,82 @@
+//! Counters describing what the server has been doing, reported by `stats`.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+#[derive(Debug, Default)]
+pub struct Metrics {
+    evaluations: AtomicU64,
+    evaluation_micros: AtomicU64,
+    slowest_evaluation_micros: AtomicU64,
+    connections: AtomicU64,
+    connections_total: AtomicU64,
+}
+
+/// Keeps a connection counted as open until it is dropped
+pub struct OpenConnection<'a> {
+    metrics: &'a Metrics,
+}
+
+impl Metrics {
+    /// Counts one evaluation of a cell's formula, which took `took`
+    pub fn record_evaluation(&self, took: Duration) {
+        let micros = u64::try_from(took.as_micros()).unwrap_or(u64::MAX);
+        self.evaluations.fetch_add(1, Ordering::Relaxed);
+        self.evaluation_micros.fetch_add(micros, Ordering::Relaxed);
+        self.slowest_evaluation_micros
+            .fetch_max(micros, Ordering::Relaxed);
+    }
+
+    /// Counts a connection as open for as long as the returned guard lives
+    pub fn open_connection(&self) -> OpenConnection<'_> {
+        self.connections.fetch_add(1, Ordering::Relaxed);
+        self.connections_total.fetch_add(1, Ordering::Relaxed);
+        OpenConnection { metrics: self }
+    }
+
+    /// Every counter by name; names ending in `_total` only ever grow
+    pub fn report(&self) -> Vec<(&'static str, u64)> {
+        let get = |counter: &AtomicU64| counter.load(Ordering::Relaxed);
+        vec![
+            ("evaluations_total", get(&self.evaluations)),
+            (
+                "evaluation_microseconds_total",
+                get(&self.evaluation_micros),
+            ),
+            (
+                "evaluation_microseconds_max",
+                get(&self.slowest_evaluation_micros),
+            ),
+            ("connections", get(&self.connections)),
+            ("connections_total", get(&self.connections_total)),
+        ]
+    }
+}
+
+impl Drop for OpenConnection<'_> {
+    fn drop(&mut self) {
+        self.metrics.connections.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_counts_evaluations_and_open_connections() {
+        let metrics = Metrics::default();
+        metrics.record_evaluation(Duration::from_micros(30));
+        metrics.record_evaluation(Duration::from_micros(10));
+        let open = metrics.open_connection();
+        {
+            let _other = metrics.open_connection();
+        }
+        let report = |metrics: &Metrics| -> Vec<u64> {
+            metrics.report().into_iter().map(|(_, n)| n).collect()
+        };
+        assert_eq!(report(&metrics), vec![2, 40, 30, 1, 2]);
+        drop(open);
+        assert_eq!(report(&metrics)[3], 0);
+    }
+}
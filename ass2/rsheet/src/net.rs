@@ -0,0 +1,232 @@
+//! The line protocol served over TCP on an async runtime.
+//!
+//! Each connection is a task rather than a thread: it reads commands a line at a time
+//! and writes one JSON reply per line, exactly as `rsheet_lib`'s `ConnectionManager`
+//! does. Commands themselves still run on blocking threads, as they take locks, but
+//! the task first waits for the cells a command reads to be recalculated, so clients
+//! waiting on slow formulas hold no threads.
+
+use std::error::Error;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use rsheet_lib::replies::Reply;
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::tcp::OwnedWriteHalf;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver};
+use tokio::task::{self, JoinSet};
+use tokio::time::Instant;
+
+use crate::command::CellRef;
+use crate::scheduler::{Scheduler, TIMED_OUT};
+use crate::session::{awaited_cells, Session};
+use crate::SheetState;
+
+/// Longest command a client may send, as for a line of an HTTP request; anything longer
+/// is answered with an error rather than buffered
+const MAX_LINE_BYTES: usize = 8 * 1024;
+
+/// Serves clients connecting to `addr` until the server is interrupted
+pub(crate) fn serve(addr: SocketAddr, state: SheetState) -> Result<(), Box<dyn Error>> {
+    let runtime = tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()?;
+    runtime.block_on(accept_connections(addr, state))?;
+    Ok(())
+}
+
+/// Accepts connections until Ctrl-C, then closes every one still open
+async fn accept_connections(addr: SocketAddr, state: SheetState) -> io::Result<()> {
+    let listener = TcpListener::bind(addr).await?;
+    let mut connections = JoinSet::new();
+
+    loop {
+        tokio::select! {
+            accepted = listener.accept() => match accepted {
+                Ok((socket, peer)) => {
+                    connections.spawn(serve_connection(socket, peer, state.clone()));
+                }
+                // Usually a client that gave up before being accepted; keep listening
+                Err(e) => eprintln!("Could not accept connection: {}", e),
+            },
+            // Connections are reaped as they close rather than kept until shutdown
+            Some(finished) = connections.join_next() => match finished {
+                Ok(Ok(())) => {}
+                Ok(Err(e)) => eprintln!("Connection error: {}", e),
+                Err(e) => eprintln!("Connection task failed: {}", e),
+            },
+            _ = tokio::signal::ctrl_c() => break,
+        }
+    }
+
+    // Stops reading from every connection; a command already running still finishes
+    connections.shutdown().await;
+    Ok(())
+}
+
+/// Reads commands from one client, replying to each before reading the next
+async fn serve_connection(
+    socket: TcpStream,
+    peer: SocketAddr,
+    state: SheetState,
+) -> io::Result<()> {
+    let metrics = Arc::clone(&state.metrics);
+    let _open = metrics.open_connection();
+    let scheduler = Arc::clone(&state.scheduler);
+    let get_timeout = state.get_timeout;
+
+    let (read, write) = socket.into_split();
+    // Replies and subscription pushes share one queue, so lines are never interleaved
+    let (replies, queued) = unbounded_channel();
+    let writer = tokio::spawn(write_replies(write, queued));
+    let pushes = replies.clone();
+    let mut session = Session::new(state, peer.to_string(), move || pushes.clone());
+
+    let mut reader = BufReader::new(read);
+    while let Some(line) = read_line(&mut reader).await? {
+        let settled = match line {
+            Ok(line) => settle(&scheduler, &awaited_cells(&line), get_timeout)
+                .await
+                .map(|()| line),
+            Err(e) => Err(e),
+        };
+        let handled = match settled {
+            Ok(line) => {
+                let (returned, handled) = task::spawn_blocking(move || {
+                    let handled = session.handle(&line);
+                    (session, handled)
+                })
+                .await?;
+                session = returned;
+                handled
+            }
+            Err(e) => vec![Reply::Error(e)],
+        };
+        // The writer only stops early once the client has gone
+        if handled
+            .into_iter()
+            .any(|reply| replies.send(reply).is_err())
+        {
+            break;
+        }
+    }
+
+    // The writer finishes once the session's and the registry's pushers are gone too
+    drop(session);
+    drop(replies);
+    writer.await?
+}
+
+/// Reads the next line of at most `MAX_LINE_BYTES`, or `None` once the client has
+/// closed the connection. A longer line is skipped to its end and read as an error.
+async fn read_line(
+    reader: &mut (impl AsyncBufRead + Unpin),
+) -> io::Result<Option<Result<String, String>>> {
+    let mut line = Vec::new();
+    (&mut *reader)
+        .take(MAX_LINE_BYTES as u64 + 1)
+        .read_until(b'\n', &mut line)
+        .await?;
+    if line.is_empty() {
+        return Ok(None);
+    }
+    if line.last() == Some(&b'\n') {
+        line.pop();
+        if line.last() == Some(&b'\r') {
+            line.pop();
+        }
+    } else if line.len() > MAX_LINE_BYTES {
+        skip_line(reader).await?;
+        return Ok(Some(Err(format!(
+            "Message longer than {} bytes",
+            MAX_LINE_BYTES
+        ))));
+    }
+    Ok(Some(
+        String::from_utf8(line).map_err(|_| "Message is not valid UTF-8".to_string()),
+    ))
+}
+
+/// Discards the rest of a line without holding on to it
+async fn skip_line(reader: &mut (impl AsyncBufRead + Unpin)) -> io::Result<()> {
+    loop {
+        let buffered = reader.fill_buf().await?;
+        let Some(end) = buffered.iter().position(|&b| b == b'\n') else {
+            if buffered.is_empty() {
+                return Ok(());
+            }
+            let skipped = buffered.len();
+            reader.consume(skipped);
+            continue;
+        };
+        reader.consume(end + 1);
+        return Ok(());
+    }
+}
+
+/// Waits for every one of `cells` to settle, giving up once `timeout` has passed in all
+async fn settle(
+    scheduler: &Scheduler,
+    cells: &[CellRef],
+    timeout: Option<Duration>,
+) -> Result<(), String> {
+    let deadline = timeout.map(|timeout| Instant::now() + timeout);
+    for cell in cells {
+        if !scheduler.settled(cell, deadline).await {
+            return Err(format!("{} {}", TIMED_OUT, cell));
+        }
+    }
+    Ok(())
+}
+
+/// Writes each queued reply to the client as a line of JSON
+async fn write_replies(
+    mut socket: OwnedWriteHalf,
+    mut queued: UnboundedReceiver<Reply>,
+) -> io::Result<()> {
+    while let Some(reply) = queued.recv().await {
+        let mut line = serde_json::to_string(&reply)?;
+        line.push('\n');
+        socket.write_all(line.as_bytes()).await?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_reads_lines_up_to_the_limit() {
+        let long = "x".repeat(MAX_LINE_BYTES);
+        let input = format!(
+            "get A1\r\n{}\n{}y{}\nget B1\n{}x",
+            long,
+            long,
+            long.repeat(3),
+            long
+        );
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap();
+        let mut reader = input.as_bytes();
+        let mut lines = Vec::new();
+        while let Some(line) = runtime.block_on(read_line(&mut reader)).unwrap() {
+            lines.push(line);
+        }
+        let too_long = Err(format!("Message longer than {} bytes", MAX_LINE_BYTES));
+        assert_eq!(
+            lines,
+            vec![
+                Ok("get A1".to_string()),
+                Ok(long),
+                too_long.clone(),
+                Ok("get B1".to_string()),
+                too_long,
+            ]
+        );
+    }
+}
@@ -0,0 +1,426 @@
+//! Write-ahead log and snapshots so a sheet survives a restart.
+//!
+//! Both files hold plain protocol commands (e.g. `set A1 5`), one per line.
+//! The snapshot reconstructs the sheet as it was when it was taken, and the
+//! log holds every mutation applied since, so recovery is simply replaying
+//! the snapshot followed by the log. A committed transaction is logged as
+//! its `set`s between `begin` and `commit`, and a batch a crash cut short is
+//! discarded so it is replayed all or not at all.
+//!
+//! Both files start with the generation of the snapshot, e.g. `generation 3`,
+//! which each snapshot bumps. A crash after a new snapshot is in place but before
+//! the log is truncated leaves a log from the previous generation, whose commands
+//! the snapshot already holds, so it is skipped rather than replayed twice.
+//!
+//! Everything logged is also streamed to any followers (see `replicate`), so a
+//! sheet kept only in memory still has a journal, just without the files.
+
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+use rsheet_lib::replies::Reply;
+use tokio::sync::mpsc::UnboundedSender;
+
+use crate::command::{CellRange, CellRef};
+use crate::display::Format;
+use crate::formula::{Function, Functions};
+use crate::replicate::Followers;
+use crate::store::Cell;
+use crate::structure::StructuralEdit;
+
+const WAL_FILE: &str = "wal.log";
+const SNAPSHOT_FILE: &str = "snapshot.txt";
+const SNAPSHOT_TMP_FILE: &str = "snapshot.txt.tmp";
+/// First word of the line both files start with, naming the snapshot's generation
+const GENERATION: &str = "generation";
+/// Lines enclosing a transaction in the log
+const BEGIN: &str = "begin";
+const COMMIT: &str = "commit";
+
+/// Append-only record of the mutations applied to the sheet, which is also what
+/// followers are sent
+pub struct Journal {
+    /// The log and snapshots on disk; `None` when the sheet is only kept in memory
+    files: Option<Files>,
+    followers: Followers,
+}
+
+struct Files {
+    dir: PathBuf,
+    wal: File,
+    /// Bumped by every snapshot; the log only holds commands after the snapshot
+    /// of the same generation
+    generation: u64,
+    since_snapshot: usize,
+    snapshot_every: usize,
+}
+
+impl Journal {
+    /// A journal that writes nothing to disk, only streaming commands to followers
+    pub fn in_memory() -> Self {
+        Journal {
+            files: None,
+            followers: Followers::default(),
+        }
+    }
+
+    /// Opens (or creates) the journal in `dir`, returning it along with the
+    /// commands that must be replayed to restore the sheet
+    pub fn open(dir: &Path, snapshot_every: usize) -> io::Result<(Self, Vec<String>)> {
+        fs::create_dir_all(dir)?;
+
+        let (mut replay, _) = read_lines(&dir.join(SNAPSHOT_FILE))?;
+        let generation = take_generation(&mut replay);
+        let (mut logged, mut wal_len) = read_lines(&dir.join(WAL_FILE))?;
+        if take_generation(&mut logged) != generation {
+            // Left by a crash mid-snapshot, so already part of the snapshot
+            logged.clear();
+            wal_len = 0;
+        }
+        if let Some(begin) = logged.iter().rposition(|(_, line)| line == BEGIN) {
+            if !logged[begin..].iter().any(|(_, line)| line == COMMIT) {
+                wal_len = logged[begin].0;
+                logged.truncate(begin);
+            }
+        }
+        let since_snapshot = logged.len();
+        replay.extend(logged);
+        let replay = replay.into_iter().map(|(_, line)| line).collect();
+
+        let mut wal = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(dir.join(WAL_FILE))?;
+        // Drop any torn tail so new entries start on a fresh line
+        wal.set_len(wal_len)?;
+        if wal_len == 0 {
+            start_log(&mut wal, generation)?;
+        }
+
+        let files = Files {
+            dir: dir.to_path_buf(),
+            wal,
+            generation,
+            since_snapshot,
+            snapshot_every: snapshot_every.max(1),
+        };
+        let journal = Journal {
+            files: Some(files),
+            followers: Followers::default(),
+        };
+        Ok((journal, replay))
+    }
+
+    /// Durably appends a `set` command to the log
+    pub fn record_set(&mut self, cell: &CellRef, expr: &str) -> io::Result<()> {
+        self.append(&[format!("set {} {}", cell, expr)], 1)
+    }
+
+    /// Durably appends several `set` commands that must be replayed all or not at all
+    pub fn record_batch(&mut self, sets: &[(CellRef, String)]) -> io::Result<()> {
+        let mut batch = vec![BEGIN.to_string()];
+        for (cell, expr) in sets {
+            batch.push(format!("set {} {}", cell, expr));
+        }
+        batch.push(COMMIT.to_string());
+        self.append(&batch, sets.len())
+    }
+
+    /// Durably appends a `clear` command, which removes a cell's expression, to the log
+    pub fn record_clear(&mut self, cell: &CellRef) -> io::Result<()> {
+        self.append(&[format!("clear {}", cell)], 1)
+    }
+
+    /// Durably appends a structural edit, such as `insert_row 5`, to the log
+    pub fn record_restructure(&mut self, edit: &StructuralEdit) -> io::Result<()> {
+        self.append(&[edit.to_string()], 1)
+    }
+
+    /// Durably appends a function definition to the log
+    pub fn record_define(&mut self, function: &Function) -> io::Result<()> {
+        self.append(&[function.to_string()], 1)
+    }
+
+    /// Durably appends a `format` command, which sets how a range's cells are shown
+    pub fn record_format(&mut self, range: &CellRange, format: &Format) -> io::Result<()> {
+        self.append(&[format_line(&range.to_string(), format)], 1)
+    }
+
+    /// Writes `lines` to the log in one go, counting them as `commands` towards the
+    /// next snapshot, then streams them to every follower
+    fn append(&mut self, lines: &[String], commands: usize) -> io::Result<()> {
+        if let Some(files) = &mut self.files {
+            let mut text = String::new();
+            for line in lines {
+                text.push_str(line);
+                text.push('\n');
+            }
+            files.wal.write_all(text.as_bytes())?;
+            files.wal.sync_data()?;
+            files.since_snapshot += commands;
+        }
+        self.followers.send(lines);
+        Ok(())
+    }
+
+    /// Starts streaming the journal to the connection `conn`, beginning with the commands
+    /// that rebuild the current functions and cells
+    pub fn follow<'a>(
+        &mut self,
+        conn: &str,
+        sender: &UnboundedSender<Reply>,
+        functions: &Functions,
+        cells: impl IntoIterator<Item = (CellRef, &'a Cell)>,
+    ) {
+        self.followers
+            .add(conn, sender, snapshot_lines(functions, cells));
+    }
+
+    /// Stops streaming to a closed connection
+    pub fn unfollow(&mut self, conn: &str) {
+        self.followers.remove(conn);
+    }
+
+    /// Whether enough commands have been logged that a snapshot is due
+    pub fn needs_snapshot(&self) -> bool {
+        self.files
+            .as_ref()
+            .is_some_and(|files| files.since_snapshot >= files.snapshot_every)
+    }
+
+    /// Writes the current functions and cells out as a snapshot and truncates the log
+    pub fn snapshot<'a>(
+        &mut self,
+        functions: &Functions,
+        cells: impl IntoIterator<Item = (CellRef, &'a Cell)>,
+    ) -> io::Result<()> {
+        let Some(files) = &mut self.files else {
+            return Ok(());
+        };
+
+        // Write to a temporary file first so a crash never leaves a torn snapshot
+        let generation = files.generation + 1;
+        let tmp_path = files.dir.join(SNAPSHOT_TMP_FILE);
+        {
+            let mut tmp = File::create(&tmp_path)?;
+            writeln!(tmp, "{} {}", GENERATION, generation)?;
+            for line in snapshot_lines(functions, cells) {
+                writeln!(tmp, "{}", line)?;
+            }
+            tmp.sync_all()?;
+        }
+        fs::rename(&tmp_path, files.dir.join(SNAPSHOT_FILE))?;
+
+        files.generation = generation;
+        files.wal.set_len(0)?;
+        start_log(&mut files.wal, generation)?;
+        files.since_snapshot = 0;
+        Ok(())
+    }
+}
+
+/// The commands that rebuild the given functions and cells: definitions first, so
+/// cells calling them evaluate correctly as soon as they are set
+fn snapshot_lines<'a>(
+    functions: &Functions,
+    cells: impl IntoIterator<Item = (CellRef, &'a Cell)>,
+) -> Vec<String> {
+    let mut definitions: Vec<_> = functions.iter().collect();
+    definitions.sort_by(|a, b| a.0.cmp(b.0));
+    let mut cells: Vec<_> = cells.into_iter().collect();
+    cells.sort_by(|a, b| a.0.cmp(&b.0));
+
+    let sets = cells.iter().filter_map(|(at, cell)| {
+        let expr = cell.expr.as_ref()?;
+        Some(format!("set {} {}", at, expr))
+    });
+    let formats = cells
+        .iter()
+        .filter(|(_, cell)| cell.format != Format::default())
+        .map(|(at, cell)| format_line(&at.key(), &cell.format));
+    definitions
+        .iter()
+        .map(|(_, function)| function.to_string())
+        .chain(sets)
+        .chain(formats)
+        .collect()
+}
+
+fn format_line(cells: &str, format: &Format) -> String {
+    format!("format {} {}", cells, format)
+}
+
+/// Removes the generation a file starts with from its lines, treating a file
+/// without one as generation 0
+fn take_generation(lines: &mut Vec<(u64, String)>) -> u64 {
+    let generation = lines.first().and_then(|(_, line)| {
+        let (word, generation) = line.split_once(' ')?;
+        generation.parse().ok().filter(|_| word == GENERATION)
+    });
+    if generation.is_some() {
+        lines.remove(0);
+    }
+    generation.unwrap_or(0)
+}
+
+/// Starts an empty log for the snapshot of the given generation
+fn start_log(wal: &mut File, generation: u64) -> io::Result<()> {
+    writeln!(wal, "{} {}", GENERATION, generation)?;
+    wal.sync_all()
+}
+
+/// Reads every complete, non-empty line of a file, each with the offset it starts at,
+/// along with the byte length they cover, treating a missing file as empty. A trailing
+/// line without a newline was torn by a crash mid-write and is dropped.
+fn read_lines(path: &Path) -> io::Result<(Vec<(u64, String)>, u64)> {
+    let contents = match fs::read_to_string(path) {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok((Vec::new(), 0)),
+        Err(e) => return Err(e),
+    };
+    let complete = match contents.rfind('\n') {
+        Some(end) => &contents[..=end],
+        None => "",
+    };
+    let mut lines = Vec::new();
+    let mut offset = 0;
+    for line in complete.split_inclusive('\n') {
+        let text = line.trim_end_matches(['\n', '\r']);
+        if !text.trim().is_empty() {
+            lines.push((offset as u64, text.to_string()));
+        }
+        offset += line.len();
+    }
+    Ok((lines, complete.len() as u64))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(key: &str) -> CellRef {
+        key.parse().unwrap()
+    }
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("rsheet-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    #[test]
+    fn test_replays_snapshot_then_log() {
+        let dir = temp_dir("replay");
+        {
+            let (mut journal, replay) = Journal::open(&dir, 2).unwrap();
+            assert!(replay.is_empty());
+            journal.record_set(&at("A1"), "1").unwrap();
+            journal.record_set(&at("B1"), "A1 + 1").unwrap();
+            assert!(journal.needs_snapshot());
+            let cell = |expr: Option<&str>, format: &str| Cell {
+                expr: expr.map(str::to_string),
+                format: format.parse().unwrap(),
+                ..Cell::default()
+            };
+            let cells = [
+                (at("B1"), cell(Some("A1 + 1"), "percent:0")),
+                (at("A1"), cell(Some("1"), "general")),
+                (at("C1"), cell(None, "red<0")),
+            ];
+            journal
+                .snapshot(&Functions::new(), cells.iter().map(|(k, c)| (k.clone(), c)))
+                .unwrap();
+            journal.record_set(&at("A1"), "2").unwrap();
+        }
+
+        let (journal, replay) = Journal::open(&dir, 2).unwrap();
+        assert_eq!(
+            replay,
+            vec![
+                "set A1 1",
+                "set B1 A1 + 1",
+                "format B1 percent:0",
+                "format C1 general red<0",
+                "set A1 2"
+            ]
+        );
+        assert!(!journal.needs_snapshot());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_skips_log_a_snapshot_already_holds() {
+        let dir = temp_dir("generation");
+        let cell = Cell {
+            expr: Some("1".to_string()),
+            ..Cell::default()
+        };
+        let stale_wal = {
+            let (mut journal, _) = Journal::open(&dir, 10).unwrap();
+            journal.record_set(&at("A1"), "1").unwrap();
+            journal
+                .record_restructure(&"insert_row 1".parse().unwrap())
+                .unwrap();
+            let stale_wal = fs::read(dir.join(WAL_FILE)).unwrap();
+            journal
+                .snapshot(&Functions::new(), [(at("A2"), &cell)])
+                .unwrap();
+            stale_wal
+        };
+        // As if the crash came between replacing the snapshot and truncating the log
+        fs::write(dir.join(WAL_FILE), stale_wal).unwrap();
+
+        {
+            let (mut journal, replay) = Journal::open(&dir, 10).unwrap();
+            assert_eq!(replay, vec!["set A2 1"]);
+            journal.record_set(&at("B1"), "2").unwrap();
+        }
+        let (_, replay) = Journal::open(&dir, 10).unwrap();
+        assert_eq!(replay, vec!["set A2 1", "set B1 2"]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_drops_torn_log_entry() {
+        let dir = temp_dir("torn");
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join(WAL_FILE), "set A1 1\nset A2 4").unwrap();
+
+        {
+            let (mut journal, replay) = Journal::open(&dir, 10).unwrap();
+            assert_eq!(replay, vec!["set A1 1"]);
+            journal.record_set(&at("A3"), "3").unwrap();
+        }
+
+        let (_, replay) = Journal::open(&dir, 10).unwrap();
+        assert_eq!(replay, vec!["set A1 1", "set A3 3"]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_replays_only_committed_batches() {
+        let dir = temp_dir("batch");
+        {
+            let (mut journal, _) = Journal::open(&dir, 10).unwrap();
+            let sets = [("A1", "1"), ("B1", "2")].map(|(k, e)| (at(k), e.to_string()));
+            journal.record_batch(&sets).unwrap();
+        }
+        // A second batch cut off before its commit
+        let mut wal = OpenOptions::new()
+            .append(true)
+            .open(dir.join(WAL_FILE))
+            .unwrap();
+        wal.write_all(b"begin\nset C1 3\n").unwrap();
+
+        {
+            let (mut journal, replay) = Journal::open(&dir, 10).unwrap();
+            assert_eq!(replay, vec!["begin", "set A1 1", "set B1 2", "commit"]);
+            journal.record_set(&at("D1"), "4").unwrap();
+        }
+        let (_, replay) = Journal::open(&dir, 10).unwrap();
+        assert_eq!(replay.last().map(String::as_str), Some("set D1 4"));
+        assert!(!replay.contains(&"set C1 3".to_string()));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,254 @@
+//! Finding the cell references inside an expression.
+//!
+//! References are written `A1`, `A1_B3` or, to reach another sheet,
+//! `Sales!A1` / `Sales!A1_B3`. A `$` before the column or row (`$A$1`,
+//! `A$1`) anchors that part so it stays put when the formula is copied.
+
+use std::ops::Range;
+
+use rsheet_lib::command::CellIdentifier;
+
+use crate::{col_number_to_letters, is_sheet_name, parse_cell_id, DEFAULT_SHEET};
+
+/// A cell or range reference as written in an expression
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Reference {
+    /// The sheet named by the reference, if it names one
+    pub sheet: Option<String>,
+    /// The cell or range part without anchors, e.g. "A1" or "A1_B3"
+    pub cells: String,
+    /// Where the reference sits in the expression
+    pub span: Range<usize>,
+}
+
+impl Reference {
+    /// The reference as seen from a cell on `current_sheet`, e.g. "Sales!A1_B3",
+    /// or just "A1_B3" when it points at the default sheet
+    pub fn qualified(&self, current_sheet: Option<&str>) -> String {
+        let sheet = self.sheet.as_deref().or(current_sheet);
+        match sheet.filter(|sheet| *sheet != DEFAULT_SHEET) {
+            Some(sheet) => format!("{}!{}", sheet, self.cells),
+            None => self.cells.clone(),
+        }
+    }
+}
+
+/// Whether `s` looks like "A1" or "A1_B3", possibly with anchors
+fn is_cells(s: &str) -> bool {
+    let mut parts = s.split('_');
+    let single = |part: Option<&str>| {
+        part.is_some_and(|p| {
+            let p = p.strip_prefix('$').unwrap_or(p);
+            let letters = p.chars().take_while(char::is_ascii_uppercase).count();
+            let digits = p[letters..].strip_prefix('$').unwrap_or(&p[letters..]);
+            letters > 0 && !digits.is_empty() && digits.chars().all(|c| c.is_ascii_digit())
+        })
+    };
+    match (parts.next(), parts.next(), parts.next()) {
+        (first, None, None) => single(first),
+        (first, Some(second), None) => single(first) && single(Some(second)),
+        _ => false,
+    }
+}
+
+fn is_ident_char(c: u8) -> bool {
+    c.is_ascii_alphanumeric() || c == b'_' || c == b'$'
+}
+
+/// Returns the position just past the string literal starting at `start`
+fn skip_literal(bytes: &[u8], start: usize) -> usize {
+    let quote = bytes[start];
+    let mut i = start + 1;
+    while i < bytes.len() && bytes[i] != quote {
+        i += if bytes[i] == b'\\' { 2 } else { 1 };
+    }
+    i + 1
+}
+
+/// Finds every cell reference in an expression, skipping over string literals
+pub fn find_references(expr: &str) -> Vec<Reference> {
+    let bytes = expr.as_bytes();
+    let mut references = Vec::new();
+    let mut i = 0;
+
+    while i < bytes.len() {
+        match bytes[i] {
+            b'"' | b'\'' | b'`' => i = skip_literal(bytes, i),
+            c if is_ident_char(c) && (i == 0 || bytes[i - 1] != b'.') => {
+                let start = i;
+                while i < bytes.len() && is_ident_char(bytes[i]) {
+                    i += 1;
+                }
+                let word = &expr[start..i];
+
+                // `Sheet!A1`, but not `A1 != B1` written without spaces
+                if bytes.get(i) == Some(&b'!') && is_sheet_name(word) {
+                    let cells_start = i + 1;
+                    let mut end = cells_start;
+                    while end < bytes.len() && is_ident_char(bytes[end]) {
+                        end += 1;
+                    }
+                    let cells = &expr[cells_start..end];
+                    if is_cells(cells) {
+                        references.push(Reference {
+                            sheet: Some(word.to_string()),
+                            cells: cells.replace('$', ""),
+                            span: start..end,
+                        });
+                        i = end;
+                        continue;
+                    }
+                }
+
+                if is_cells(word) {
+                    references.push(Reference {
+                        sheet: None,
+                        cells: word.replace('$', ""),
+                        span: start..i,
+                    });
+                }
+            }
+            _ => i += 1,
+        }
+    }
+    references
+}
+
+/// Rebuilds an expression with each reference replaced by what `f` makes of it, given
+/// the reference as written. Returns `None` if `f` does for any reference.
+pub fn map_references(
+    expr: &str,
+    mut f: impl FnMut(&Reference, &str) -> Option<String>,
+) -> Option<String> {
+    let mut mapped = String::with_capacity(expr.len());
+    let mut last = 0;
+
+    for reference in find_references(expr) {
+        let written = &expr[reference.span.clone()];
+        mapped.push_str(&expr[last..reference.span.start]);
+        mapped.push_str(&f(&reference, written)?);
+        last = reference.span.end;
+    }
+    mapped.push_str(&expr[last..]);
+    Some(mapped)
+}
+
+/// Splits a reference as written into its sheet prefix (including the `!`) and its
+/// anchored cells, one for a single cell or two for a range
+pub fn split_written(written: &str) -> Option<(&str, Vec<Anchored>)> {
+    let (prefix, cells) = match written.rsplit_once('!') {
+        Some((sheet, cells)) => (&written[..=sheet.len()], cells),
+        None => ("", written),
+    };
+    let cells = cells
+        .split('_')
+        .map(Anchored::parse)
+        .collect::<Option<Vec<_>>>()?;
+    Some((prefix, cells))
+}
+
+/// Writes a reference back out from its sheet prefix and anchored cells
+pub fn join_written(prefix: &str, cells: &[Anchored]) -> String {
+    let cells: Vec<String> = cells.iter().map(Anchored::to_string).collect();
+    format!("{}{}", prefix, cells.join("_"))
+}
+
+/// One cell of a reference along with which of its parts are anchored, e.g. "A$1"
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Anchored {
+    pub id: CellIdentifier,
+    pub fixed_col: bool,
+    pub fixed_row: bool,
+}
+
+impl Anchored {
+    fn parse(cell: &str) -> Option<Self> {
+        let (fixed_col, rest) = match cell.strip_prefix('$') {
+            Some(rest) => (true, rest),
+            None => (false, cell),
+        };
+        let letters = rest.chars().take_while(char::is_ascii_uppercase).count();
+        let (fixed_row, digits) = match rest[letters..].strip_prefix('$') {
+            Some(digits) => (true, digits),
+            None => (false, &rest[letters..]),
+        };
+        let id = parse_cell_id(&format!("{}{}", &rest[..letters], digits))?;
+        Some(Anchored {
+            id,
+            fixed_col,
+            fixed_row,
+        })
+    }
+}
+
+impl std::fmt::Display for Anchored {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let anchor = |fixed: bool| if fixed { "$" } else { "" };
+        write!(
+            f,
+            "{}{}{}{}",
+            anchor(self.fixed_col),
+            col_number_to_letters(self.id.col),
+            anchor(self.fixed_row),
+            self.id.row + 1
+        )
+    }
+}
+
+/// Rewrites an expression as if it were copied `cols` columns right and `rows` rows down,
+/// moving every reference except its anchored parts. Returns `None` if a reference
+/// would move off the sheet.
+pub fn shift(expr: &str, cols: i64, rows: i64) -> Option<String> {
+    map_references(expr, |_, written| {
+        let (prefix, mut cells) = split_written(written)?;
+        for cell in &mut cells {
+            if !cell.fixed_col {
+                cell.id.col = u32::try_from(i64::from(cell.id.col) + cols).ok()?;
+            }
+            if !cell.fixed_row {
+                cell.id.row = u32::try_from(i64::from(cell.id.row) + rows).ok()?;
+            }
+        }
+        Some(join_written(prefix, &cells))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_finds_plain_and_qualified_references() {
+        let refs = find_references(r#"A1 + Sales!B2 * sum(Costs!A1_A3) + "C3""#);
+        let found: Vec<_> = refs
+            .iter()
+            .map(|r| (r.sheet.as_deref(), &*r.cells))
+            .collect();
+        assert_eq!(
+            found,
+            vec![
+                (None, "A1"),
+                (Some("Sales"), "B2"),
+                (Some("Costs"), "A1_A3")
+            ]
+        );
+    }
+
+    #[test]
+    fn test_ignores_negation() {
+        let refs = find_references("A1!=B1");
+        let found: Vec<_> = refs.iter().map(|r| &*r.cells).collect();
+        assert_eq!(found, vec!["A1", "B1"]);
+    }
+
+    #[test]
+    fn test_shift_keeps_anchored_parts() {
+        let expr = "A1 + $A$1 + A$1 + $A1 + sum(Sales!B1_B3)";
+        assert_eq!(
+            shift(expr, 1, 2).unwrap(),
+            "B3 + $A$1 + B$1 + $A3 + sum(Sales!C3_C5)"
+        );
+        assert_eq!(find_references("$A$1 + 1")[0].cells, "A1");
+        assert_eq!(shift("A2", 0, -2), None);
+    }
+}
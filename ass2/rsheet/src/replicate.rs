@@ -0,0 +1,208 @@
+//! Streaming the journal to follower servers, and following a leader.
+//!
+//! A follower connects to its leader like any client and sends `replicate`. The
+//! leader replies with the whole sheet as commands, as a snapshot would hold it,
+//! then with every command it journals from then on, each as a `log` value:
+//!
+//! ```text
+//! {"Value":["log","set A1 5"]}
+//! ```
+//!
+//! The follower applies them in order and only serves reads. A leader with users
+//! configured only streams to a follower that logs in first (`--follow-user`).
+//!
+//! Once its connection to the leader is lost and it can't reconnect for a few
+//! seconds, the follower takes over and accepts changes itself. If the leader
+//! refuses it or sends something it can't apply, it stops following but stays
+//! read-only. A follower cut off from a leader that is still running would take
+//! over all the same, leaving both accepting changes, so the old leader must be
+//! stopped, or kept from clients, before the follower is relied on.
+
+use std::collections::HashMap;
+use std::io::{BufRead, BufReader, Lines, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::thread;
+use std::time::Duration;
+
+use rsheet_lib::cell_value::CellValue;
+use rsheet_lib::replies::Reply;
+use tokio::sync::mpsc::UnboundedSender;
+
+/// Name the leader gives each command it streams
+const LOG_KEY: &str = "log";
+
+/// How many times a follower tries to reconnect to a leader it lost, and how long
+/// it waits before each attempt, before taking over
+const RECONNECT_ATTEMPTS: u32 = 5;
+const RECONNECT_DELAY: Duration = Duration::from_secs(1);
+
+/// Connections streaming the journal, keyed by their connection's id
+#[derive(Default)]
+pub struct Followers {
+    senders: HashMap<String, UnboundedSender<Reply>>,
+}
+
+impl Followers {
+    /// Starts streaming to the connection `conn`, first sending it `catch_up`, the
+    /// commands that rebuild the sheet as it is now
+    pub fn add(
+        &mut self,
+        conn: &str,
+        sender: &UnboundedSender<Reply>,
+        catch_up: impl IntoIterator<Item = String>,
+    ) {
+        if catch_up
+            .into_iter()
+            .all(|command| sender.send(log_reply(command)).is_ok())
+        {
+            self.senders.insert(conn.to_string(), sender.clone());
+        }
+    }
+
+    /// Stops streaming to a closed connection
+    pub fn remove(&mut self, conn: &str) {
+        self.senders.remove(conn);
+    }
+
+    /// Streams newly journaled commands to every follower
+    pub fn send(&mut self, commands: &[String]) {
+        // A failed send means the connection has gone away
+        self.senders.retain(|_, sender| {
+            commands
+                .iter()
+                .all(|command| sender.send(log_reply(command.clone())).is_ok())
+        });
+    }
+}
+
+fn log_reply(command: String) -> Reply {
+    Reply::Value(LOG_KEY.to_string(), CellValue::String(command))
+}
+
+/// Why a follower stopped following its leader
+#[derive(Debug, PartialEq)]
+pub enum Stopped {
+    /// The connection failed or closed, as it does when the leader goes away
+    Lost(String),
+    /// The leader refused to be followed or sent something unreadable, or a change it
+    /// sent couldn't be applied here
+    Failed(String),
+}
+
+impl From<String> for Stopped {
+    fn from(e: String) -> Self {
+        Stopped::Failed(e)
+    }
+}
+
+/// A follower's connection to its leader
+pub struct Leader {
+    addr: SocketAddr,
+    /// The user name and password to log in with, if the leader needs them
+    login: Option<(String, String)>,
+    lines: Lines<BufReader<TcpStream>>,
+}
+
+impl Leader {
+    /// Connects to the leader at `addr`, logs in, and asks it to start streaming
+    pub fn connect(addr: SocketAddr, login: Option<(String, String)>) -> Result<Self, String> {
+        let error = |e: std::io::Error| format!("Could not follow {}: {}", addr, e);
+        let mut socket = TcpStream::connect(addr).map_err(error)?;
+        let mut request = String::new();
+        if let Some((user, password)) = &login {
+            request.push_str(&format!("login {} {}\n", user, password));
+        }
+        request.push_str("replicate\n");
+        socket.write_all(request.as_bytes()).map_err(error)?;
+        Ok(Leader {
+            addr,
+            login,
+            lines: BufReader::new(socket).lines(),
+        })
+    }
+
+    /// Tries to connect to the same leader again, giving up after a few seconds
+    pub fn reconnect(&self) -> Option<Self> {
+        (0..RECONNECT_ATTEMPTS).find_map(|_| {
+            thread::sleep(RECONNECT_DELAY);
+            Leader::connect(self.addr, self.login.clone()).ok()
+        })
+    }
+
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// Waits for the next command the leader logs
+    pub fn next_command(&mut self) -> Result<String, Stopped> {
+        let line = match self.lines.next() {
+            Some(Ok(line)) => line,
+            Some(Err(e)) => return Err(Stopped::Lost(e.to_string())),
+            None => return Err(Stopped::Lost("connection closed".to_string())),
+        };
+        match serde_json::from_str(&line) {
+            Ok(Reply::Value(key, CellValue::String(command))) if key == LOG_KEY => Ok(command),
+            Ok(Reply::Error(e)) => Err(Stopped::Failed(e)),
+            _ => Err(Stopped::Failed(format!("unexpected reply {}", line))),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::sync::mpsc::unbounded_channel;
+
+    use super::*;
+
+    #[test]
+    fn test_logs_in_then_tells_refusals_from_lost_connections() {
+        use std::io::Read;
+        use std::net::TcpListener;
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let leader = thread::spawn(move || {
+            let (mut socket, _) = listener.accept().unwrap();
+            let mut request = [0; 64];
+            let read = socket.read(&mut request).unwrap();
+            let line = |reply: Reply| format!("{}\n", serde_json::to_string(&reply).unwrap());
+            socket
+                .write_all(line(log_reply("set A1 1".to_string())).as_bytes())
+                .unwrap();
+            socket
+                .write_all(line(Reply::Error("Not logged in".to_string())).as_bytes())
+                .unwrap();
+            String::from_utf8_lossy(&request[..read]).into_owned()
+        });
+
+        let login = Some(("follower".to_string(), "secret".to_string()));
+        let mut following = Leader::connect(addr, login).unwrap();
+        assert_eq!(following.next_command(), Ok("set A1 1".to_string()));
+        assert_eq!(
+            following.next_command(),
+            Err(Stopped::Failed("Not logged in".to_string()))
+        );
+        assert_eq!(leader.join().unwrap(), "login follower secret\nreplicate\n");
+        assert!(matches!(following.next_command(), Err(Stopped::Lost(_))));
+    }
+
+    #[test]
+    fn test_followers_catch_up_then_stream() {
+        let mut followers = Followers::default();
+        let (sender, mut received) = unbounded_channel();
+        let (gone, closed) = unbounded_channel();
+        drop(closed);
+
+        followers.add("f1", &sender, ["set A1 1".to_string()]);
+        followers.add("f2", &gone, Vec::new());
+        followers.send(&["set A1 2".to_string()]);
+        assert_eq!(followers.senders.len(), 1);
+
+        for expected in ["set A1 1", "set A1 2"] {
+            assert_eq!(received.try_recv(), Ok(log_reply(expected.to_string())));
+        }
+        followers.remove("f1");
+        followers.send(&["set A1 3".to_string()]);
+        assert!(received.try_recv().is_err());
+    }
+}
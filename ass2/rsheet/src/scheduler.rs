@@ -0,0 +1,402 @@
+//! Hands stale cells out to the evaluator pool as soon as they can be computed.
+//!
+//! A changed cell and everything downstream of it is marked dirty. A dirty
+//! cell is ready once none of the cells it reads is dirty or being evaluated,
+//! so independent cells are evaluated concurrently while a cell never sees a
+//! half-finished input. A cell is settled (safe to `get`) when it is neither
+//! dirty nor being evaluated. Threads wait for that with `wait_until_settled`,
+//! and async tasks with `settled`, which holds no thread while it waits.
+
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::sync::{Condvar, Mutex};
+use std::time::Duration;
+
+use tokio::sync::watch;
+use tokio::time::{self, Instant};
+
+use crate::command::CellRef;
+use crate::graph::{Component, DepGraph};
+
+/// Start of the error given when a cell doesn't settle in time, followed by the cell
+pub const TIMED_OUT: &str = "Timed out waiting for";
+
+/// A cell handed out for evaluation
+pub struct Task {
+    pub cell: CellRef,
+    /// The cell's mark when it was handed out; a newer mark makes the result stale
+    mark: u64,
+}
+
+#[derive(Default)]
+struct Pending {
+    /// Cells waiting to be evaluated
+    dirty: HashSet<CellRef>,
+    /// Cells being evaluated right now
+    running: HashSet<CellRef>,
+    /// Dirty cells known to be slow, which are handed out only when nothing else is ready
+    slow: HashSet<CellRef>,
+    /// Cells that may have become ready, split by speed
+    normal_queue: VecDeque<CellRef>,
+    delayed_queue: VecDeque<CellRef>,
+    queued: HashSet<CellRef>,
+    /// Last mark of each cell; bumped whenever it is marked dirty or settled by a cycle
+    marks: HashMap<CellRef, u64>,
+    next_mark: u64,
+}
+
+#[derive(Default)]
+pub struct Scheduler {
+    pending: Mutex<Pending>,
+    /// Signalled whenever a cell may have become ready
+    changed: Condvar,
+    /// Signalled whenever a cell settles, waking anyone waiting on its value
+    settled: Condvar,
+    /// Sent whenever a cell settles, for async tasks waiting on its value
+    settled_async: watch::Sender<()>,
+}
+
+impl Pending {
+    fn bump(&mut self, cell: &CellRef) {
+        self.next_mark += 1;
+        self.marks.insert(cell.clone(), self.next_mark);
+    }
+
+    fn enqueue(&mut self, cell: &CellRef) {
+        if !self.queued.insert(cell.clone()) {
+            return;
+        }
+        if self.slow.contains(cell) {
+            self.delayed_queue.push_back(cell.clone());
+        } else {
+            self.normal_queue.push_back(cell.clone());
+        }
+    }
+
+    fn is_settled(&self, cell: &CellRef) -> bool {
+        !self.dirty.contains(cell) && !self.running.contains(cell)
+    }
+
+    fn is_idle(&self) -> bool {
+        self.dirty.is_empty() && self.running.is_empty()
+    }
+}
+
+impl Scheduler {
+    /// Marks every cell in a recalculation order stale. Cyclic components can never be
+    /// evaluated, so they are settled at once and handed to `on_cycle` to store their error;
+    /// cells for which `is_slow` holds are deferred while faster cells are ready.
+    pub fn schedule(
+        &self,
+        order: &[Component],
+        is_slow: impl Fn(&CellRef) -> bool,
+        mut on_cycle: impl FnMut(&[CellRef]),
+    ) {
+        let mut pending = self.pending.lock().unwrap();
+        for component in order {
+            for cell in &component.cells {
+                pending.bump(cell);
+                if component.cyclic {
+                    pending.dirty.remove(cell);
+                    pending.slow.remove(cell);
+                } else {
+                    pending.dirty.insert(cell.clone());
+                    if is_slow(cell) {
+                        pending.slow.insert(cell.clone());
+                    } else {
+                        pending.slow.remove(cell);
+                    }
+                    pending.enqueue(cell);
+                }
+            }
+            if component.cyclic {
+                on_cycle(&component.cells);
+                self.announce_settled();
+            }
+        }
+        self.changed.notify_all();
+    }
+
+    /// Blocks until some dirty cell is ready to evaluate, then hands it out
+    pub fn next_task<G: AsRef<DepGraph>>(&self, deps: &Mutex<G>) -> Task {
+        let mut pending = self.pending.lock().unwrap();
+        loop {
+            while let Some(cell) = pending
+                .normal_queue
+                .pop_front()
+                .or_else(|| pending.delayed_queue.pop_front())
+            {
+                pending.queued.remove(&cell);
+                if !pending.dirty.contains(&cell) || pending.running.contains(&cell) {
+                    continue;
+                }
+                // A blocked cell is queued again when whatever blocks it finishes
+                let ready = {
+                    let deps_guard = deps.lock().unwrap();
+                    let mut precedents = deps_guard.as_ref().precedents(&cell);
+                    precedents.all(|precedent| pending.is_settled(precedent))
+                };
+                if ready {
+                    pending.dirty.remove(&cell);
+                    pending.slow.remove(&cell);
+                    pending.running.insert(cell.clone());
+                    let mark = pending.marks.get(&cell).copied().unwrap_or_default();
+                    return Task { cell, mark };
+                }
+            }
+            pending = self.changed.wait(pending).unwrap();
+        }
+    }
+
+    /// Finishes a task. `publish` is told whether the result is still current (nothing
+    /// marked the cell since it was handed out) and runs before anyone sees the cell settle.
+    pub fn finish<G: AsRef<DepGraph>>(
+        &self,
+        task: &Task,
+        deps: &Mutex<G>,
+        publish: impl FnOnce(bool),
+    ) {
+        let mut pending = self.pending.lock().unwrap();
+        pending.running.remove(&task.cell);
+        let current = pending.marks.get(&task.cell) == Some(&task.mark);
+        publish(current);
+
+        // The cell itself, if it changed again meanwhile, and anything it was blocking
+        pending.enqueue(&task.cell);
+        let dependents: Vec<CellRef> = {
+            let deps_guard = deps.lock().unwrap();
+            deps_guard
+                .as_ref()
+                .dependents(&task.cell)
+                .cloned()
+                .collect()
+        };
+        for dependent in dependents {
+            if pending.dirty.contains(&dependent) {
+                pending.enqueue(&dependent);
+            }
+        }
+        self.changed.notify_all();
+        if pending.is_settled(&task.cell) {
+            self.announce_settled();
+        }
+    }
+
+    /// Wakes everything waiting for a cell to settle, whether thread or task
+    fn announce_settled(&self) {
+        self.settled.notify_all();
+        self.settled_async.send_replace(());
+    }
+
+    /// How much work is waiting, by name: the two ready queues (which may hold cells that
+    /// turn out to be blocked) and the cells stale or being evaluated
+    pub fn report(&self) -> Vec<(&'static str, u64)> {
+        let pending = self.pending.lock().unwrap();
+        vec![
+            ("normal_queue", pending.normal_queue.len() as u64),
+            ("delayed_queue", pending.delayed_queue.len() as u64),
+            ("dirty_cells", pending.dirty.len() as u64),
+            ("running_cells", pending.running.len() as u64),
+        ]
+    }
+
+    /// Blocks until a cell's value is final, giving up after `timeout` if there is one.
+    /// Returns whether the cell settled.
+    pub fn wait_until_settled(&self, cell: &CellRef, timeout: Option<Duration>) -> bool {
+        let pending = self.pending.lock().unwrap();
+        match timeout {
+            Some(timeout) => {
+                let (pending, _) = self
+                    .settled
+                    .wait_timeout_while(pending, timeout, |pending| !pending.is_settled(cell))
+                    .unwrap();
+                pending.is_settled(cell)
+            }
+            None => {
+                let _pending = self
+                    .settled
+                    .wait_while(pending, |pending| !pending.is_settled(cell))
+                    .unwrap();
+                true
+            }
+        }
+    }
+
+    /// Waits until a cell's value is final without blocking the thread, giving up at
+    /// `deadline` if there is one. Returns whether the cell settled.
+    pub async fn settled(&self, cell: &CellRef, deadline: Option<Instant>) -> bool {
+        // Subscribed before checking, so a cell settling in between isn't missed
+        let mut announced = self.settled_async.subscribe();
+        loop {
+            if self.pending.lock().unwrap().is_settled(cell) {
+                return true;
+            }
+            let woken = match deadline {
+                Some(deadline) => time::timeout_at(deadline, announced.changed())
+                    .await
+                    .is_ok(),
+                None => announced.changed().await.is_ok(),
+            };
+            if !woken {
+                return self.pending.lock().unwrap().is_settled(cell);
+            }
+        }
+    }
+
+    /// Blocks until every cell is settled, so nothing is left half-recalculated
+    pub fn wait_until_idle(&self) {
+        let pending = self.pending.lock().unwrap();
+        let _pending = self
+            .settled
+            .wait_while(pending, |pending| !pending.is_idle())
+            .unwrap();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(key: &str) -> CellRef {
+        key.parse().unwrap()
+    }
+
+    fn is_settled(scheduler: &Scheduler, cell: &str) -> bool {
+        scheduler.wait_until_settled(&at(cell), Some(Duration::ZERO))
+    }
+
+    fn graph(edges: &[(&str, &[&str])]) -> Mutex<DepGraph> {
+        let mut graph = DepGraph::default();
+        for (cell, precedents) in edges {
+            graph.set_precedents(&at(cell), precedents.iter().map(|p| at(p)));
+        }
+        Mutex::new(graph)
+    }
+
+    fn order(deps: &Mutex<DepGraph>, start: &str) -> Vec<Component> {
+        deps.lock().unwrap().recalc_order(&[at(start)])
+    }
+
+    #[test]
+    fn test_hands_out_independent_cells_together() {
+        // B1 and C1 both read A1, D1 reads both
+        let deps = graph(&[("B1", &["A1"]), ("C1", &["A1"]), ("D1", &["B1", "C1"])]);
+        let scheduler = Scheduler::default();
+        let order = order(&deps, "A1");
+        scheduler.schedule(&order, |_| false, |_| {});
+
+        let a1 = scheduler.next_task(&deps);
+        assert_eq!(a1.cell, at("A1"));
+        assert!(!is_settled(&scheduler, "D1"));
+        scheduler.finish(&a1, &deps, |current| assert!(current));
+
+        let first = scheduler.next_task(&deps);
+        let second = scheduler.next_task(&deps);
+        let mut both = vec![first.cell.key(), second.cell.key()];
+        both.sort();
+        assert_eq!(both, vec!["B1", "C1"]);
+
+        scheduler.finish(&first, &deps, |_| {});
+        scheduler.finish(&second, &deps, |_| {});
+        let d1 = scheduler.next_task(&deps);
+        assert_eq!(d1.cell, at("D1"));
+        scheduler.finish(&d1, &deps, |_| {});
+        assert!(is_settled(&scheduler, "D1"));
+    }
+
+    #[test]
+    fn test_result_is_stale_if_marked_while_running() {
+        let deps = graph(&[]);
+        let scheduler = Scheduler::default();
+        let order = order(&deps, "A1");
+        scheduler.schedule(&order, |_| false, |_| {});
+
+        let task = scheduler.next_task(&deps);
+        scheduler.schedule(&order, |_| false, |_| {});
+        scheduler.finish(&task, &deps, |current| assert!(!current));
+        assert!(!is_settled(&scheduler, "A1"));
+
+        let again = scheduler.next_task(&deps);
+        scheduler.finish(&again, &deps, |current| assert!(current));
+        assert!(is_settled(&scheduler, "A1"));
+    }
+
+    #[test]
+    fn test_slow_cells_go_last() {
+        let deps = graph(&[]);
+        let scheduler = Scheduler::default();
+        for cell in ["A1", "B1"] {
+            scheduler.schedule(&order(&deps, cell), |cell| *cell == at("A1"), |_| {});
+        }
+        assert_eq!(
+            scheduler.report(),
+            vec![
+                ("normal_queue", 1),
+                ("delayed_queue", 1),
+                ("dirty_cells", 2),
+                ("running_cells", 0)
+            ]
+        );
+        assert_eq!(scheduler.next_task(&deps).cell, at("B1"));
+        assert_eq!(scheduler.next_task(&deps).cell, at("A1"));
+    }
+
+    #[test]
+    fn test_waiter_wakes_when_cell_settles() {
+        let deps = graph(&[]);
+        let scheduler = Scheduler::default();
+        let order = order(&deps, "A1");
+        scheduler.schedule(&order, |_| false, |_| {});
+        assert!(!scheduler.wait_until_settled(&at("A1"), Some(Duration::from_millis(10))));
+
+        std::thread::scope(|scope| {
+            let waiter = scope.spawn(|| scheduler.wait_until_settled(&at("A1"), None));
+            let task = scheduler.next_task(&deps);
+            scheduler.finish(&task, &deps, |_| {});
+            assert!(waiter.join().unwrap());
+        });
+    }
+
+    #[test]
+    fn test_async_waiter_wakes_when_cell_settles() {
+        let deps = graph(&[]);
+        let scheduler = Scheduler::default();
+        scheduler.schedule(&order(&deps, "A1"), |_| false, |_| {});
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_time()
+            .build()
+            .unwrap();
+        let soon = Instant::now() + Duration::from_millis(10);
+        assert!(!runtime.block_on(scheduler.settled(&at("A1"), Some(soon))));
+
+        std::thread::scope(|scope| {
+            scope.spawn(|| {
+                let task = scheduler.next_task(&deps);
+                std::thread::sleep(Duration::from_millis(10));
+                scheduler.finish(&task, &deps, |_| {});
+            });
+            assert!(runtime.block_on(scheduler.settled(&at("A1"), None)));
+        });
+    }
+
+    #[test]
+    fn test_idle_once_every_cell_settles() {
+        let deps = graph(&[("B1", &["A1"])]);
+        let scheduler = Scheduler::default();
+        let order = order(&deps, "A1");
+        scheduler.schedule(&order, |_| false, |_| {});
+
+        std::thread::scope(|scope| {
+            let waiter = scope.spawn(|| scheduler.wait_until_idle());
+            for _ in 0..2 {
+                let task = scheduler.next_task(&deps);
+                assert!(!waiter.is_finished());
+                scheduler.finish(&task, &deps, |_| {});
+            }
+            waiter.join().unwrap();
+        });
+        assert_eq!(
+            scheduler.report()[2..],
+            [("dirty_cells", 0), ("running_cells", 0)]
+        );
+    }
+}
@@ -0,0 +1,554 @@
+//! One client's conversation with the server: who it logged in as, the transaction
+//! it has open, and where its subscriptions are pushed.
+
+use rsheet_lib::cell_value::CellValue;
+use rsheet_lib::replies::Reply;
+use tokio::sync::mpsc::UnboundedSender;
+
+use crate::command::{CellRef, Request, MAX_RANGE_CELLS};
+use crate::display::display_name;
+use crate::explain::explain;
+use crate::formula::FormulaError;
+use crate::{
+    commit, copy_cell, csv_file, define_function, edit_cell, format_cells, get_range, restructure,
+    set_cell, wait_for_range, wait_for_value, SheetState,
+};
+
+/// Start of the error refusing a change while following a leader, followed by its address
+pub(crate) const FOLLOWING: &str = "Read-only while following";
+
+pub(crate) struct Session {
+    state: SheetState,
+    /// The connection's id, which undo history and subscriptions are kept under
+    conn: String,
+    /// The user the connection logged in as
+    user: Option<String>,
+    /// `set`s held back by `begin`; anything uncommitted is dropped with the session
+    transaction: Option<Vec<(CellRef, String)>>,
+    /// Where replies to subscriptions are pushed, once the connection subscribes to something
+    pusher: Option<UnboundedSender<Reply>>,
+    open_pusher: Box<dyn Fn() -> UnboundedSender<Reply> + Send>,
+}
+
+impl Session {
+    /// Starts a session for the connection `conn`, which calls `open_pusher` the first
+    /// time it needs somewhere to push replies to
+    pub(crate) fn new(
+        state: SheetState,
+        conn: String,
+        open_pusher: impl Fn() -> UnboundedSender<Reply> + Send + 'static,
+    ) -> Self {
+        Session {
+            state,
+            conn,
+            user: None,
+            transaction: None,
+            pusher: None,
+            open_pusher: Box::new(open_pusher),
+        }
+    }
+
+    /// Carries out one message from the client, returning the replies to send back
+    pub(crate) fn handle(&mut self, msg: &str) -> Vec<Reply> {
+        let request = msg.parse::<Request>().and_then(|request| {
+            authorise(&self.state, self.user.as_deref(), &request)?;
+            Ok(request)
+        });
+        match request {
+            Ok(
+                Request::Import { .. }
+                | Request::Copy { .. }
+                | Request::Format { .. }
+                | Request::Restructure { .. }
+                | Request::Undo { .. }
+                | Request::Redo { .. }
+                | Request::Define { .. }
+                | Request::Login { .. },
+            ) if self.transaction.is_some() => vec![Reply::Error(
+                "Only set and read-only commands can be used in a transaction".to_string(),
+            )],
+            Ok(Request::Get { cell, formatted }) => {
+                let mut replies = vec![match wait_for_value(&self.state, &cell) {
+                    // A cell that can't be evaluated at all, rather than one whose
+                    // formula failed, is an error reply naming the original cause
+                    Ok(CellValue::Error(msg))
+                        if matches!(
+                            msg.parse(),
+                            Ok(FormulaError::Upstream { .. } | FormulaError::Cycle(_))
+                        ) =>
+                    {
+                        Reply::Error(msg)
+                    }
+                    Ok(value) => Reply::Value(cell.key(), value),
+                    Err(e) => Reply::Error(e),
+                }];
+                if let (true, [Reply::Value(key, value)]) = (formatted, replies.as_slice()) {
+                    let display = self.state.cells.lock().unwrap().display(&cell, value);
+                    replies.push(Reply::Value(
+                        display_name(key),
+                        CellValue::String(display.to_string()),
+                    ));
+                }
+                replies
+            }
+            Ok(Request::GetRange { range, formatted }) => {
+                match get_range(&self.state, &range, formatted) {
+                    Ok((rows, displays)) => {
+                        let name = range.to_string();
+                        let display = displays.map(|displays| {
+                            Reply::Value(display_name(&name), CellValue::String(displays))
+                        });
+                        let values = Reply::Value(name, CellValue::String(rows));
+                        std::iter::once(values).chain(display).collect()
+                    }
+                    Err(e) => vec![Reply::Error(e)],
+                }
+            }
+            Ok(Request::Format { range, format }) => {
+                match format_cells(&self.state, &range, &format) {
+                    Ok(()) => Vec::new(),
+                    Err(e) => vec![Reply::Error(e)],
+                }
+            }
+            Ok(Request::Set { cell, expr }) => {
+                if let Some(sets) = &mut self.transaction {
+                    sets.push((cell, expr));
+                    return Vec::new();
+                }
+                match edit_cell(&self.state, &self.conn, &cell, &expr) {
+                    Ok(()) => Vec::new(),
+                    Err(e) => vec![Reply::Error(e)],
+                }
+            }
+            Ok(Request::Subscribe { range }) => {
+                let sender = self.pusher.get_or_insert_with(|| (self.open_pusher)());
+                let mut subscriptions = self.state.subscriptions.lock().unwrap();
+                subscriptions.subscribe(&self.conn, range, sender);
+                Vec::new()
+            }
+            Ok(Request::Unsubscribe { range }) => {
+                let mut subscriptions = self.state.subscriptions.lock().unwrap();
+                if subscriptions.unsubscribe(&self.conn, &range) {
+                    return Vec::new();
+                }
+                vec![Reply::Error(format!("Not subscribed to {}", range))]
+            }
+            Ok(Request::Import { path, top_left }) => {
+                let imported = csv_file::resolve(self.state.files_dir.as_deref(), &path)
+                    .and_then(|path| csv_file::read_cells(&path, top_left.id))
+                    .and_then(|cells| {
+                        let cells: Vec<(CellRef, String)> = cells
+                            .into_iter()
+                            .map(|(id, expr)| {
+                                let sheet = top_left.sheet.clone();
+                                (CellRef { sheet, id }, expr)
+                            })
+                            .collect();
+                        // Check every cell first so a protected one can't stop it halfway
+                        cells.iter().try_for_each(|(cell, _)| {
+                            authorise_cell(&self.state, self.user.as_deref(), cell)
+                        })?;
+                        // Imported all at once, like a committed transaction
+                        commit(&self.state, &self.conn, cells)
+                    });
+                match imported {
+                    Ok(()) => Vec::new(),
+                    Err(e) => vec![Reply::Error(e)],
+                }
+            }
+            Ok(Request::Export { range, path }) => {
+                let written =
+                    csv_file::resolve(self.state.files_dir.as_deref(), &path).and_then(|path| {
+                        wait_for_range(&self.state, &range)?;
+                        let rows = self.state.cells.lock().unwrap().range(
+                            range.sheet.as_deref(),
+                            range.start,
+                            range.end,
+                        );
+                        csv_file::write_cells(&path, &rows)
+                    });
+                match written {
+                    Ok(()) => Vec::new(),
+                    Err(e) => vec![Reply::Error(e)],
+                }
+            }
+            Ok(Request::Copy { source, targets }) => {
+                match copy_cell(&self.state, &self.conn, &source, &targets) {
+                    Ok(()) => Vec::new(),
+                    Err(e) => vec![Reply::Error(e)],
+                }
+            }
+            Ok(Request::Restructure { edit }) => match restructure(&self.state, &edit) {
+                Ok(()) => Vec::new(),
+                Err(e) => vec![Reply::Error(e)],
+            },
+            Ok(Request::History { cell }) => {
+                let history = self.state.history.lock().unwrap();
+                let versions = history.versions(&cell);
+                if versions.is_empty() {
+                    vec![Reply::Error(format!("No history for {}", cell))]
+                } else {
+                    // One line per version, e.g. `A1@2 = "B1 + 1"`; `None` once cleared
+                    versions
+                        .iter()
+                        .enumerate()
+                        .map(|(i, expr)| {
+                            let value = match expr {
+                                Some(expr) => CellValue::String(expr.clone()),
+                                None => CellValue::None,
+                            };
+                            Reply::Value(format!("{}@{}", cell, i + 1), value)
+                        })
+                        .collect()
+                }
+            }
+            Ok(Request::Stats) => {
+                let mut counters = self.state.scheduler.report();
+                counters.extend(self.state.metrics.report());
+                counters
+                    .into_iter()
+                    .map(|(name, n)| Reply::Value(name.to_string(), CellValue::Int(n as i64)))
+                    .collect()
+            }
+            Ok(Request::Deps { cell }) => {
+                let precedents = {
+                    let cells_guard = self.state.cells.lock().unwrap();
+                    let precedents = cells_guard.deps.precedents(&cell);
+                    precedents.cloned().collect()
+                };
+                match neighbour_values(&self.state, precedents) {
+                    replies if replies.is_empty() => {
+                        vec![Reply::Error(format!("{} reads no cells", cell))]
+                    }
+                    replies => replies,
+                }
+            }
+            Ok(Request::Dependents { cell }) => {
+                let dependents = {
+                    let cells_guard = self.state.cells.lock().unwrap();
+                    let dependents = cells_guard.deps.dependents(&cell);
+                    dependents.cloned().collect()
+                };
+                match neighbour_values(&self.state, dependents) {
+                    replies if replies.is_empty() => {
+                        vec![Reply::Error(format!("No cells read {}", cell))]
+                    }
+                    replies => replies,
+                }
+            }
+            Ok(Request::Explain { cell }) => {
+                // Explain the value a `get` would reply with
+                match wait_for_value(&self.state, &cell) {
+                    Ok(_) => explain(&self.state.cells.lock().unwrap(), &cell),
+                    Err(e) => vec![Reply::Error(e)],
+                }
+            }
+            Ok(Request::Undo { global }) => {
+                let mut history = self.state.history.lock().unwrap();
+                let scope = (!global).then_some(self.conn.as_str());
+                let undone = history.undo(scope, |edit| {
+                    authorise_cell(&self.state, self.user.as_deref(), &edit.cell)?;
+                    set_cell(&self.state, &edit.cell, edit.before.as_deref()).map(drop)
+                });
+                match undone {
+                    Ok(true) => Vec::new(),
+                    Ok(false) => vec![Reply::Error("Nothing to undo".to_string())],
+                    Err(e) => vec![Reply::Error(e)],
+                }
+            }
+            Ok(Request::Redo { global }) => {
+                let mut history = self.state.history.lock().unwrap();
+                let scope = (!global).then_some(self.conn.as_str());
+                let redone = history.redo(scope, |edit| {
+                    authorise_cell(&self.state, self.user.as_deref(), &edit.cell)?;
+                    set_cell(&self.state, &edit.cell, edit.after.as_deref()).map(drop)
+                });
+                match redone {
+                    Ok(true) => Vec::new(),
+                    Ok(false) => vec![Reply::Error("Nothing to redo".to_string())],
+                    Err(e) => vec![Reply::Error(e)],
+                }
+            }
+            Ok(Request::Define { function }) => match define_function(&self.state, function) {
+                Ok(()) => Vec::new(),
+                Err(e) => vec![Reply::Error(e)],
+            },
+            Ok(Request::Login {
+                user: name,
+                password,
+            }) => match &self.state.access {
+                Some(access) => {
+                    // A failed attempt logs out whoever was logged in before
+                    self.user = None;
+                    match access.login(&name, &password) {
+                        Ok(()) => {
+                            self.user = Some(name);
+                            Vec::new()
+                        }
+                        Err(e) => vec![Reply::Error(e)],
+                    }
+                }
+                None => vec![Reply::Error("No users are configured".to_string())],
+            },
+            Ok(Request::Begin) => {
+                if self.transaction.is_some() {
+                    vec![Reply::Error("Already in a transaction".to_string())]
+                } else {
+                    self.transaction = Some(Vec::new());
+                    Vec::new()
+                }
+            }
+            Ok(Request::Commit) => match self.transaction.take() {
+                Some(sets) => match commit(&self.state, &self.conn, sets) {
+                    Ok(()) => Vec::new(),
+                    Err(e) => vec![Reply::Error(e)],
+                },
+                None => vec![Reply::Error("No transaction to commit".to_string())],
+            },
+            Ok(Request::Rollback) => match self.transaction.take() {
+                Some(_) => Vec::new(),
+                None => vec![Reply::Error("No transaction to roll back".to_string())],
+            },
+            Ok(Request::Replicate) => {
+                let sender = self.pusher.get_or_insert_with(|| (self.open_pusher)());
+                let mut journal = self.state.journal.lock().unwrap();
+                let functions_guard = self.state.functions.lock().unwrap();
+                let cells_guard = self.state.cells.lock().unwrap();
+                journal.follow(&self.conn, sender, &functions_guard, cells_guard.iter());
+                Vec::new()
+            }
+            Err(e) => vec![Reply::Error(e)],
+        }
+    }
+}
+
+/// The cells a message waits on before it can be answered, so a caller that can't afford
+/// to block may wait for them to settle first. Ranges too large to `get` are left to fail.
+pub(crate) fn awaited_cells(msg: &str) -> Vec<CellRef> {
+    match msg.parse::<Request>() {
+        Ok(Request::Get { cell, .. } | Request::Explain { cell }) => vec![cell],
+        Ok(Request::GetRange { range, .. } | Request::Export { range, .. })
+            if range.cell_count() <= MAX_RANGE_CELLS =>
+        {
+            range.cells().collect()
+        }
+        _ => Vec::new(),
+    }
+}
+
+impl Drop for Session {
+    fn drop(&mut self) {
+        // Dropping the registries' senders lets the pusher finish
+        self.state.subscriptions.lock().unwrap().remove(&self.conn);
+        self.state.journal.lock().unwrap().unfollow(&self.conn);
+    }
+}
+
+/// Checks that the connection's user may make a request before any of it is carried out
+fn authorise(state: &SheetState, user: Option<&str>, request: &Request) -> Result<(), String> {
+    if let Some(leader) = *state.leader.lock().unwrap() {
+        if matches!(
+            request,
+            Request::Set { .. }
+                | Request::Import { .. }
+                | Request::Copy { .. }
+                | Request::Format { .. }
+                | Request::Restructure { .. }
+                | Request::Undo { .. }
+                | Request::Redo { .. }
+                | Request::Define { .. }
+                | Request::Commit
+        ) {
+            return Err(format!("{} {}", FOLLOWING, leader));
+        }
+    }
+    let Some(access) = &state.access else {
+        return Ok(());
+    };
+    match request {
+        Request::Login { .. } => Ok(()),
+        Request::Get { .. }
+        | Request::GetRange { .. }
+        | Request::Subscribe { .. }
+        | Request::Unsubscribe { .. }
+        | Request::History { .. }
+        | Request::Stats
+        | Request::Deps { .. }
+        | Request::Dependents { .. }
+        | Request::Explain { .. }
+        | Request::Begin
+        | Request::Commit
+        | Request::Rollback
+        | Request::Replicate => access.check_read(user),
+        Request::Set { cell, .. } => access.check_cell(user, cell),
+        Request::Copy { targets, .. } => access.check_range(user, targets),
+        Request::Format { range, .. } => access.check_range(user, range),
+        Request::Restructure { edit } => access.check_sheet(user, edit.sheet.as_deref()),
+        // Imports, undo and redo also check each cell they change as they find it;
+        // imports and exports touch files on the server, so they need write access too
+        Request::Import { .. }
+        | Request::Export { .. }
+        | Request::Undo { .. }
+        | Request::Redo { .. }
+        | Request::Define { .. } => access.check_write(user),
+    }
+}
+
+/// Checks that the connection's user may change a cell
+fn authorise_cell(state: &SheetState, user: Option<&str>, cell: &CellRef) -> Result<(), String> {
+    match &state.access {
+        Some(access) => access.check_cell(user, cell),
+        None => Ok(()),
+    }
+}
+
+/// Replies with the current value of each of a cell's neighbours in the graph, ordered
+/// by sheet, column and row
+fn neighbour_values(state: &SheetState, mut cells: Vec<CellRef>) -> Vec<Reply> {
+    cells.sort();
+    let cells_guard = state.cells.lock().unwrap();
+    cells
+        .into_iter()
+        .map(|cell| Reply::Value(cell.key(), cells_guard.value(&cell)))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use tokio::sync::mpsc::unbounded_channel;
+
+    use super::*;
+    use crate::{launch, ServerConfig};
+
+    fn session() -> Session {
+        session_with(ServerConfig::default())
+    }
+
+    fn session_with(config: ServerConfig) -> Session {
+        let config = ServerConfig {
+            volatile_interval: None,
+            ..config
+        };
+        let state = launch(&config).unwrap();
+        Session::new(state, "test".to_string(), || unbounded_channel().0)
+    }
+
+    fn value(name: &str, json: &str) -> Reply {
+        Reply::Value(name.to_string(), CellValue::String(json.to_string()))
+    }
+
+    fn error(message: &str) -> Vec<Reply> {
+        vec![Reply::Error(message.to_string())]
+    }
+
+    #[test]
+    fn test_transaction_errors() {
+        let mut session = session();
+        assert_eq!(session.handle("commit"), error("No transaction to commit"));
+        assert_eq!(
+            session.handle("rollback"),
+            error("No transaction to roll back")
+        );
+
+        assert_eq!(session.handle("begin"), Vec::new());
+        assert_eq!(session.handle("begin"), error("Already in a transaction"));
+        assert_eq!(
+            session.handle("define f(x) = x"),
+            error("Only set and read-only commands can be used in a transaction")
+        );
+        assert_eq!(session.handle("set A1 7"), Vec::new());
+        assert_eq!(session.handle("commit"), Vec::new());
+        assert_eq!(
+            session.handle("get A1"),
+            vec![Reply::Value("A1".to_string(), CellValue::Int(7))]
+        );
+        assert_eq!(
+            session.handle("rollback"),
+            error("No transaction to roll back")
+        );
+    }
+
+    #[test]
+    fn test_reports_cycles_until_they_are_broken() {
+        let mut session = session();
+        for set in ["set A1 B1", "set B1 A1", "set C1 A1 + 1"] {
+            assert_eq!(session.handle(set), Vec::new());
+        }
+        let cycle = "#CYCLE! circular dependency between A1, B1";
+        assert_eq!(session.handle("get A1"), error(cycle));
+        assert_eq!(session.handle("get B1"), error(cycle));
+        assert_eq!(
+            session.handle("get C1"),
+            error(&format!("{} (from A1)", cycle))
+        );
+
+        assert_eq!(session.handle("set B1 5"), Vec::new());
+        assert_eq!(
+            session.handle("get A1"),
+            vec![Reply::Value("A1".to_string(), CellValue::Int(5))]
+        );
+        assert_eq!(
+            session.handle("get C1"),
+            vec![Reply::Value("C1".to_string(), CellValue::Int(6))]
+        );
+    }
+
+    #[test]
+    fn test_gets_ranges_row_by_row() {
+        let mut session = session();
+        for set in [
+            "set A1 1",
+            "set B1 2",
+            "set A2 A1 + B1",
+            "format A1_A2 currency:0",
+        ] {
+            assert_eq!(session.handle(set), Vec::new());
+        }
+        assert_eq!(
+            session.handle("get A1_B2"),
+            vec![value("A1_B2", "[[1,2],[3,null]]")]
+        );
+        assert_eq!(
+            session.handle("get Sales!A1_A2"),
+            vec![value("Sales!A1_A2", "[[null],[null]]")]
+        );
+        assert_eq!(
+            session.handle("get A1_B2 formatted"),
+            vec![
+                value("A1_B2", "[[1,2],[3,null]]"),
+                value(
+                    "A1_B2.display",
+                    r#"[[{"styles":[],"text":"$1"},{"styles":[],"text":"2"}],[{"styles":[],"text":"$3"},{"styles":[],"text":""}]]"#
+                ),
+            ]
+        );
+        assert_eq!(
+            session.handle("get A1_Z1000"),
+            error("A1_Z1000 has more than 10000 cells")
+        );
+    }
+
+    #[test]
+    fn test_range_waits_once_for_all_its_cells() {
+        // One evaluator works through the slow cells one after another
+        let mut session = session_with(ServerConfig {
+            workers: 1,
+            get_timeout: Some(Duration::from_millis(300)),
+            ..ServerConfig::default()
+        });
+        for row in 1..=3 {
+            let set = format!("set A{} sleep_then(200, {})", row, row);
+            assert_eq!(session.handle(&set), Vec::new());
+        }
+        let started = Instant::now();
+        let replies = session.handle("get A1_A3");
+        assert!(
+            matches!(&replies[..], [Reply::Error(e)] if e.starts_with("Timed out waiting for")),
+            "{:?}",
+            replies
+        );
+        assert!(started.elapsed() < Duration::from_millis(500));
+    }
+}
@@ -0,0 +1,240 @@
+//! Sparse storage for the sheet's cells.
+//!
+//! Each sheet keeps only the cells that have an expression or a value, ordered
+//! by column and then row, so a range is read with one ordered scan per column
+//! rather than a lookup per cell. Everything known about a cell lives in its
+//! `Cell`, and the dependency graph sits alongside, all behind a single lock.
+//! Cells are looked up by `CellRef`, so nothing is parsed or formatted to find
+//! one; keys such as "Sales!B2" are only written out for clients and the journal.
+
+use std::collections::{BTreeMap, HashMap};
+
+use rsheet_lib::cell_value::CellValue;
+use rsheet_lib::command::CellIdentifier;
+
+use crate::command::CellRef;
+use crate::display::Format;
+use crate::formula::Behaviour;
+use crate::graph::DepGraph;
+
+/// Everything stored about one cell
+#[derive(Debug, Clone, PartialEq)]
+pub struct Cell {
+    pub expr: Option<String>,
+    /// The value last calculated from `expr`
+    pub value: CellValue,
+    pub behaviour: Behaviour,
+    /// How clients should show `value`
+    pub format: Format,
+}
+
+impl Default for Cell {
+    fn default() -> Self {
+        Cell {
+            expr: None,
+            value: CellValue::None,
+            behaviour: Behaviour::default(),
+            format: Format::default(),
+        }
+    }
+}
+
+type Cells = BTreeMap<CellIdentifier, Cell>;
+
+#[derive(Default)]
+pub struct Store {
+    /// The default sheet's cells
+    default_sheet: Cells,
+    /// Every other sheet's cells, by sheet name
+    sheets: HashMap<String, Cells>,
+    /// What each cell reads, and what reads it
+    pub deps: DepGraph,
+}
+
+impl Store {
+    /// The cell at `at`, if it has anything in it
+    pub fn get(&self, at: &CellRef) -> Option<&Cell> {
+        self.cells(at.sheet.as_deref())?.get(&at.id)
+    }
+
+    /// The value of the cell at `at`; empty if there is nothing there
+    pub fn value(&self, at: &CellRef) -> CellValue {
+        self.get(at)
+            .map_or(CellValue::None, |cell| cell.value.clone())
+    }
+
+    /// The expression of the cell at `at`
+    pub fn expr(&self, at: &CellRef) -> Option<&str> {
+        self.get(at)?.expr.as_deref()
+    }
+
+    /// How to show `value` in the cell at `at`
+    pub fn display(&self, at: &CellRef, value: &CellValue) -> serde_json::Value {
+        match self.get(at) {
+            Some(cell) => cell.format.display(value),
+            None => Format::default().display(value),
+        }
+    }
+
+    /// Changes the cell at `at`, which starts out empty if there is nothing there yet,
+    /// and forgets it again if the change leaves it empty
+    pub fn update<T>(&mut self, at: &CellRef, change: impl FnOnce(&mut Cell) -> T) -> T {
+        let cells = match &at.sheet {
+            Some(sheet) => self.sheets.entry(sheet.clone()).or_default(),
+            None => &mut self.default_sheet,
+        };
+        let cell = cells.entry(at.id).or_default();
+        let changed = change(cell);
+        if *cell == Cell::default() {
+            cells.remove(&at.id);
+        }
+        changed
+    }
+
+    /// The values of a rectangle of cells on `sheet`, row by row
+    pub fn range(
+        &self,
+        sheet: Option<&str>,
+        start: CellIdentifier,
+        end: CellIdentifier,
+    ) -> Vec<Vec<CellValue>> {
+        let width = (end.col + 1).saturating_sub(start.col) as usize;
+        let height = (end.row + 1).saturating_sub(start.row) as usize;
+        let mut rows = vec![vec![CellValue::None; width]; height];
+        for (id, cell) in self.within(sheet, start, end) {
+            rows[(id.row - start.row) as usize][(id.col - start.col) as usize] = cell.value.clone();
+        }
+        rows
+    }
+
+    /// Every stored cell within a rectangle on `sheet`, column by column
+    pub fn within(
+        &self,
+        sheet: Option<&str>,
+        start: CellIdentifier,
+        end: CellIdentifier,
+    ) -> impl Iterator<Item = (&CellIdentifier, &Cell)> {
+        // An upside-down range would make the column scans panic
+        let cells = self.cells(sheet).filter(|_| start.row <= end.row);
+        (start.col..=end.col).flat_map(move |col| {
+            let column = CellIdentifier {
+                col,
+                row: start.row,
+            }..=CellIdentifier { col, row: end.row };
+            cells
+                .into_iter()
+                .flat_map(move |cells| cells.range(column.clone()))
+        })
+    }
+
+    /// Every cell with an expression, with where it is
+    pub fn exprs(&self) -> impl Iterator<Item = (CellRef, &str)> {
+        self.iter()
+            .filter_map(|(at, cell)| Some((at, cell.expr.as_deref()?)))
+    }
+
+    /// Every cell whose formula is volatile
+    pub fn volatile(&self) -> impl Iterator<Item = CellRef> + '_ {
+        self.iter()
+            .filter(|(_, cell)| cell.behaviour.volatile)
+            .map(|(at, _)| at)
+    }
+
+    /// Removes and returns every cell, with where it was
+    pub fn take_all(&mut self) -> Vec<(CellRef, Cell)> {
+        let default_sheet = std::mem::take(&mut self.default_sheet)
+            .into_iter()
+            .map(|(id, cell)| (CellRef { sheet: None, id }, cell));
+        let sheets = std::mem::take(&mut self.sheets)
+            .into_iter()
+            .flat_map(|(sheet, cells)| {
+                cells.into_iter().map(move |(id, cell)| {
+                    let sheet = Some(sheet.clone());
+                    (CellRef { sheet, id }, cell)
+                })
+            });
+        default_sheet.chain(sheets).collect()
+    }
+
+    /// Every cell, with where it is
+    pub fn iter(&self) -> impl Iterator<Item = (CellRef, &Cell)> {
+        let default_sheet = self
+            .default_sheet
+            .iter()
+            .map(|(&id, cell)| (CellRef { sheet: None, id }, cell));
+        let sheets = self.sheets.iter().flat_map(|(sheet, cells)| {
+            cells.iter().map(move |(&id, cell)| {
+                let sheet = Some(sheet.clone());
+                (CellRef { sheet, id }, cell)
+            })
+        });
+        default_sheet.chain(sheets)
+    }
+
+    fn cells(&self, sheet: Option<&str>) -> Option<&Cells> {
+        match sheet {
+            Some(sheet) => self.sheets.get(sheet),
+            None => Some(&self.default_sheet),
+        }
+    }
+}
+
+/// Lets the scheduler walk the dependencies while holding the store's lock
+impl AsRef<DepGraph> for Store {
+    fn as_ref(&self) -> &DepGraph {
+        &self.deps
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn id(col: u32, row: u32) -> CellIdentifier {
+        CellIdentifier { col, row }
+    }
+
+    fn at(key: &str) -> CellRef {
+        key.parse().unwrap()
+    }
+
+    #[test]
+    fn test_keeps_only_cells_with_something_in_them() {
+        let mut store = Store::default();
+        store.update(&at("A1"), |cell| cell.expr = Some("1".to_string()));
+        store.update(&at("Sales!B2"), |cell| cell.value = CellValue::Int(2));
+        assert_eq!(store.expr(&at("Sheet1!A1")), Some("1"));
+        assert_eq!(store.value(&at("Sales!B2")), CellValue::Int(2));
+        assert_eq!(store.value(&at("Costs!B2")), CellValue::None);
+
+        store.update(&at("A1"), |cell| cell.expr = None);
+        assert_eq!(store.get(&at("A1")), None);
+        let all: Vec<CellRef> = store.take_all().into_iter().map(|(at, _)| at).collect();
+        assert_eq!(all, vec![at("Sales!B2")]);
+    }
+
+    #[test]
+    fn test_reads_ranges_row_by_row() {
+        let mut store = Store::default();
+        for (key, n) in [("A1", 1), ("B2", 4), ("A3", 5), ("C2", 9), ("Sales!A2", 7)] {
+            store.update(&at(key), |cell| cell.value = CellValue::Int(n));
+        }
+        let int = CellValue::Int;
+        let none = || CellValue::None;
+        assert_eq!(
+            store.range(None, id(0, 0), id(1, 2)),
+            vec![
+                vec![int(1), none()],
+                vec![none(), int(4)],
+                vec![int(5), none()]
+            ]
+        );
+        assert_eq!(
+            store.range(Some("Sales"), id(0, 1), id(0, 1)),
+            vec![vec![int(7)]]
+        );
+        assert_eq!(store.range(Some("Costs"), id(0, 0), id(0, 1)).len(), 2);
+        assert!(store.range(None, id(2, 0), id(1, 0))[0].is_empty());
+        assert!(store.range(None, id(0, 2), id(0, 1)).is_empty());
+    }
+}
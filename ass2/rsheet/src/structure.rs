@@ -0,0 +1,231 @@
+//! Inserting and deleting whole rows and columns.
+//!
+//! Cells at or beyond the edit move along with it, and every formula that
+//! reads the edited sheet is rewritten to follow them: ranges grow or shrink,
+//! and references to deleted cells become `#REF!`, which evaluates to an error.
+//! Cells an insert pushes past the last row or column are dropped as if deleted.
+
+use std::fmt;
+use std::str::FromStr;
+
+use rsheet_lib::command::CellIdentifier;
+
+use crate::command::CellRef;
+use crate::reference::{join_written, map_references, split_written, Anchored};
+use crate::{col_number_to_letters, is_sheet_name, parse_cell_id, split_sheet};
+
+/// What a reference to a deleted cell is rewritten to
+pub const REF_ERROR: &str = "#REF!";
+
+/// The last row or column a cell can be in, as keys name them from 1
+const LAST_INDEX: u32 = u32::MAX - 1;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Axis {
+    Row,
+    Col,
+}
+
+/// `insert_row 5`, `delete_col Sales!C` and the like
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StructuralEdit {
+    /// The sheet being edited, `None` for the default sheet
+    pub sheet: Option<String>,
+    pub axis: Axis,
+    /// The 0-indexed row or column inserted before or deleted
+    pub index: u32,
+    pub delete: bool,
+}
+
+impl StructuralEdit {
+    /// Where a row or column ends up, or `None` if it is deleted or pushed off the sheet
+    fn move_index(&self, i: u32) -> Option<u32> {
+        match (self.delete, i.cmp(&self.index)) {
+            (_, std::cmp::Ordering::Less) => Some(i),
+            (false, _) if i < LAST_INDEX => Some(i + 1),
+            (false, _) => None,
+            (true, std::cmp::Ordering::Equal) => None,
+            (true, std::cmp::Ordering::Greater) => Some(i - 1),
+        }
+    }
+
+    fn coord<'a>(&self, id: &'a mut CellIdentifier) -> &'a mut u32 {
+        match self.axis {
+            Axis::Row => &mut id.row,
+            Axis::Col => &mut id.col,
+        }
+    }
+
+    /// Where a stored cell moves to, or `None` if it is deleted
+    pub fn move_cell(&self, cell: &CellRef) -> Option<CellRef> {
+        let mut moved = cell.clone();
+        if moved.sheet == self.sheet {
+            let coord = self.coord(&mut moved.id);
+            *coord = self.move_index(*coord)?;
+        }
+        Some(moved)
+    }
+
+    /// Rewrites the expression of a cell on `current_sheet` to follow the moved cells
+    pub fn rewrite(&self, expr: &str, current_sheet: Option<&str>) -> String {
+        let rewritten = map_references(expr, |reference, written| {
+            let qualified = reference.qualified(current_sheet);
+            if split_sheet(&qualified).0 != self.sheet.as_deref() {
+                return Some(written.to_string());
+            }
+            let (prefix, mut cells) = split_written(written)?;
+            Some(match self.move_cells(&mut cells) {
+                Some(()) => join_written(prefix, &cells),
+                None => REF_ERROR.to_string(),
+            })
+        });
+        rewritten.unwrap_or_else(|| expr.to_string())
+    }
+
+    /// Moves the cells of a reference, returning `None` if everything it named is deleted
+    fn move_cells(&self, cells: &mut [Anchored]) -> Option<()> {
+        let [first, last] = cells else {
+            for cell in cells {
+                let coord = self.coord(&mut cell.id);
+                *coord = self.move_index(*coord)?;
+            }
+            return Some(());
+        };
+
+        let (a, b) = (*self.coord(&mut first.id), *self.coord(&mut last.id));
+        let (low, high) = if a <= b { (first, last) } else { (last, first) };
+        let (low, high) = (self.coord(&mut low.id), self.coord(&mut high.id));
+        if self.delete {
+            // A range keeps whatever survives of it
+            if *low == self.index && *high == self.index {
+                return None;
+            }
+            if *low > self.index {
+                *low -= 1;
+            }
+            if *high >= self.index {
+                *high -= 1;
+            }
+        } else {
+            // A range loses whatever is pushed off the sheet
+            *low = self.move_index(*low)?;
+            *high = self.move_index(*high).unwrap_or(LAST_INDEX);
+        }
+        Some(())
+    }
+}
+
+impl FromStr for StructuralEdit {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let error = || format!("Error parsing request: {}", s);
+        let mut parts = s.split_whitespace();
+        let (Some(command), Some(arg), None) = (parts.next(), parts.next(), parts.next()) else {
+            return Err(error());
+        };
+        let (delete, axis) = match command {
+            "insert_row" => (false, Axis::Row),
+            "delete_row" => (true, Axis::Row),
+            "insert_col" => (false, Axis::Col),
+            "delete_col" => (true, Axis::Col),
+            _ => return Err(error()),
+        };
+
+        let (sheet, position) = split_sheet(arg);
+        if sheet.is_some_and(|sheet| !is_sheet_name(sheet)) {
+            return Err(error());
+        }
+        let index = match axis {
+            Axis::Row if position.chars().all(|c| c.is_ascii_digit()) => {
+                parse_cell_id(&format!("A{}", position)).map(|id| id.row)
+            }
+            Axis::Col if position.chars().all(|c| c.is_ascii_alphabetic()) => {
+                parse_cell_id(&format!("{}1", position)).map(|id| id.col)
+            }
+            _ => None,
+        }
+        .ok_or_else(error)?;
+
+        Ok(StructuralEdit {
+            sheet: sheet.map(str::to_string),
+            axis,
+            index,
+            delete,
+        })
+    }
+}
+
+impl fmt::Display for StructuralEdit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let verb = if self.delete { "delete" } else { "insert" };
+        let (noun, position) = match self.axis {
+            Axis::Row => ("row", (self.index + 1).to_string()),
+            Axis::Col => ("col", col_number_to_letters(self.index)),
+        };
+        write!(f, "{}_{} ", verb, noun)?;
+        if let Some(sheet) = &self.sheet {
+            write!(f, "{}!", sheet)?;
+        }
+        write!(f, "{}", position)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn moved(edit: &StructuralEdit, key: &str) -> Option<String> {
+        edit.move_cell(&key.parse().unwrap()).map(|cell| cell.key())
+    }
+
+    #[test]
+    fn test_delete_row_rewrites_references() {
+        let edit: StructuralEdit = "delete_row 3".parse().unwrap();
+        assert_eq!(moved(&edit, "A2").as_deref(), Some("A2"));
+        assert_eq!(moved(&edit, "A3"), None);
+        assert_eq!(moved(&edit, "B4").as_deref(), Some("B3"));
+        assert_eq!(moved(&edit, "Sales!B4").as_deref(), Some("Sales!B4"));
+
+        assert_eq!(
+            edit.rewrite("A3 + $B$4 + sum(A1_A10) + Sales!A4", None),
+            "#REF! + $B$3 + sum(A1_A9) + Sales!A4"
+        );
+        assert_eq!(edit.rewrite("sum(A3_B3) + A4", None), "sum(#REF!) + A3");
+        // Seen from another sheet, unqualified references point at that sheet
+        assert_eq!(
+            edit.rewrite("A4 + Sheet1!A4", Some("Sales")),
+            "A4 + Sheet1!A3"
+        );
+    }
+
+    #[test]
+    fn test_insert_col_grows_ranges_it_lands_in() {
+        let edit: StructuralEdit = "insert_col Sales!B".parse().unwrap();
+        assert_eq!(edit.to_string(), "insert_col Sales!B");
+        assert_eq!(moved(&edit, "Sales!B1").as_deref(), Some("Sales!C1"));
+        assert_eq!(
+            edit.rewrite("sum(A1_C1) + A1 + B$1", Some("Sales")),
+            "sum(A1_D1) + A1 + C$1"
+        );
+    }
+
+    #[test]
+    fn test_insert_drops_cells_pushed_off_the_sheet() {
+        let edit: StructuralEdit = "insert_row 1".parse().unwrap();
+        assert_eq!(moved(&edit, "A4294967295"), None);
+        assert_eq!(moved(&edit, "A4294967294").as_deref(), Some("A4294967295"));
+        assert_eq!(
+            edit.rewrite("A4294967295 + sum(A4294967290_A4294967295)", None),
+            "#REF! + sum(A4294967291_A4294967295)"
+        );
+        assert_eq!(
+            edit.rewrite("sum(A4294967295_B4294967295)", None),
+            "sum(#REF!)"
+        );
+
+        let edit: StructuralEdit = "insert_col A".parse().unwrap();
+        let last_col = col_number_to_letters(LAST_INDEX);
+        assert_eq!(moved(&edit, &format!("{}1", last_col)), None);
+    }
+}
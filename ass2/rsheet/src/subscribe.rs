@@ -0,0 +1,150 @@
+//! Registry of connections watching ranges of cells for changes.
+
+use std::collections::HashMap;
+
+use rsheet_lib::cell_value::CellValue;
+use rsheet_lib::replies::Reply;
+use tokio::sync::mpsc::UnboundedSender;
+
+use crate::command::{CellRange, CellRef};
+
+/// A connection's watched ranges and the channel its pushes are written through
+struct Subscriber {
+    ranges: Vec<CellRange>,
+    sender: UnboundedSender<Reply>,
+}
+
+#[derive(Default)]
+pub struct Subscriptions {
+    /// Subscribers keyed by their connection's id
+    subscribers: HashMap<String, Subscriber>,
+}
+
+impl Subscriptions {
+    /// Starts pushing changes within `range` to the connection `conn`
+    pub fn subscribe(&mut self, conn: &str, range: CellRange, sender: &UnboundedSender<Reply>) {
+        let subscriber = self
+            .subscribers
+            .entry(conn.to_string())
+            .or_insert_with(|| Subscriber {
+                ranges: Vec::new(),
+                sender: sender.clone(),
+            });
+        if !subscriber.ranges.contains(&range) {
+            subscriber.ranges.push(range);
+        }
+    }
+
+    /// Stops watching `range`, returning whether `conn` was subscribed to it
+    pub fn unsubscribe(&mut self, conn: &str, range: &CellRange) -> bool {
+        let Some(subscriber) = self.subscribers.get_mut(conn) else {
+            return false;
+        };
+        let before = subscriber.ranges.len();
+        subscriber.ranges.retain(|r| r != range);
+        let removed = subscriber.ranges.len() != before;
+        if subscriber.ranges.is_empty() {
+            self.subscribers.remove(conn);
+        }
+        removed
+    }
+
+    /// Drops every subscription held by a closed connection
+    pub fn remove(&mut self, conn: &str) {
+        self.subscribers.remove(conn);
+    }
+
+    /// Pushes a recalculated value to every connection watching the cell
+    pub fn notify(&mut self, cell: &CellRef, value: &CellValue) {
+        if self.subscribers.is_empty() {
+            return;
+        }
+        // A failed send means the connection has gone away
+        self.subscribers.retain(|_, subscriber| {
+            !subscriber.ranges.iter().any(|range| range.contains(cell))
+                || subscriber
+                    .sender
+                    .send(Reply::Value(cell.key(), value.clone()))
+                    .is_ok()
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver};
+
+    use super::*;
+
+    fn range(s: &str) -> CellRange {
+        s.parse().unwrap()
+    }
+
+    fn pushed(receiver: &mut UnboundedReceiver<Reply>) -> Vec<String> {
+        std::iter::from_fn(|| receiver.try_recv().ok())
+            .map(|reply| match reply {
+                Reply::Value(name, _) => name,
+                Reply::Error(e) => e,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_pushes_changes_within_subscribed_ranges() {
+        let mut subscriptions = Subscriptions::default();
+        let (sender, mut receiver) = unbounded_channel();
+        subscriptions.subscribe("a", range("A1_B2"), &sender);
+        subscriptions.subscribe("a", range("Sales!C3"), &sender);
+
+        for key in ["A1", "B2", "C1", "Sales!A1", "Sales!C3", "Sheet1!A2"] {
+            subscriptions.notify(&key.parse().unwrap(), &CellValue::Int(1));
+        }
+        assert_eq!(pushed(&mut receiver), vec!["A1", "B2", "Sales!C3", "A2"]);
+    }
+
+    #[test]
+    fn test_subscribes_to_each_range_once() {
+        let mut subscriptions = Subscriptions::default();
+        let (sender, mut receiver) = unbounded_channel();
+        subscriptions.subscribe("a", range("A1_A2"), &sender);
+        subscriptions.subscribe("a", range("A2_A1"), &sender);
+        // Overlapping ranges still push each change only once
+        subscriptions.subscribe("a", range("A1"), &sender);
+        subscriptions.notify(&"A1".parse().unwrap(), &CellValue::Int(1));
+        assert_eq!(pushed(&mut receiver), vec!["A1"]);
+
+        assert!(subscriptions.unsubscribe("a", &range("A1_A2")));
+        assert!(!subscriptions.unsubscribe("a", &range("A1_A2")));
+        assert!(!subscriptions.unsubscribe("b", &range("A1")));
+        subscriptions.notify(&"A2".parse().unwrap(), &CellValue::Int(2));
+        assert!(pushed(&mut receiver).is_empty());
+
+        assert!(subscriptions.unsubscribe("a", &range("A1")));
+        assert!(subscriptions.subscribers.is_empty());
+    }
+
+    #[test]
+    fn test_drops_subscribers_whose_connection_closed() {
+        let mut subscriptions = Subscriptions::default();
+        let (open, mut receiver) = unbounded_channel();
+        let (closed, _) = unbounded_channel();
+        subscriptions.subscribe("open", range("A1"), &open);
+        subscriptions.subscribe("closed", range("A1"), &closed);
+        subscriptions.subscribe("elsewhere", range("B1"), &closed);
+
+        subscriptions.notify(&"A1".parse().unwrap(), &CellValue::Int(1));
+        assert_eq!(pushed(&mut receiver), vec!["A1"]);
+        let mut left: Vec<&str> = subscriptions
+            .subscribers
+            .keys()
+            .map(String::as_str)
+            .collect();
+        left.sort();
+        // Only a push finds out the connection has gone
+        assert_eq!(left, vec!["elsewhere", "open"]);
+
+        subscriptions.remove("open");
+        subscriptions.notify(&"A1".parse().unwrap(), &CellValue::Int(2));
+        assert!(pushed(&mut receiver).is_empty());
+    }
+}